@@ -1,18 +1,22 @@
 // #![allow(unused)]
-// #[allow(unused_variables)]
 
-use std;
 use std::io;
 use std::fmt;
 use std::str;
+use std::io::{BufWriter, ErrorKind, Read, Write};
 use std::fs::File;
-use std::io::{Error, ErrorKind, Read, Write, BufReader, Seek, SeekFrom};
-use std::collections::HashMap;
-use std::mem::MaybeUninit;
+use std::collections::VecDeque;
 use byteorder::{ByteOrder, LittleEndian};
 
-use flate2::{FlushDecompress, Decompress, Status};
+use flate2::{Compression, FlushDecompress, Decompress, Status};
 use crc32fast::Hasher;
+use crate::records::{RecordIterator, SalvagedRecord};
+use crate::pairing::{PairedOutputs, PairedWriter};
+use crate::tags::{AuxField, AuxValue};
+use crate::modifications::{ModCheck, check_modifications, stored_modifications};
+use crate::header::BamHeader;
+use crate::bgzf::BgzfWriter;
+use crate::salvager::{MissingQual, OutputFormat, Salvager, SalvageReport};
 
 macro_rules! function {
     () => {{
@@ -45,7 +49,7 @@ macro_rules! function {
 //     }    
 // }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum BamErrorKind {
     NoBAMFile = 0,
     BlockCorrupted = 1,
@@ -55,13 +59,14 @@ pub enum BamErrorKind {
     BufferTerminated = 4,
     InconsistentChecksum = 6,
     InconsistentBlockSize = 7,
+    OutputFailed = 8,
 }
 
 #[derive(Debug)]
 pub struct BamHandleError {
-    line:u32,
-    function:String,
-    kind:BamErrorKind,
+    pub(crate) line:u32,
+    pub(crate) function:String,
+    pub(crate) kind:BamErrorKind,
 }
 
 impl fmt::Display for BamHandleError {
@@ -75,12 +80,101 @@ impl fmt::Display for BamHandleError {
             BamErrorKind::BufferTerminated => "Buffer terminated",
             BamErrorKind::InconsistentChecksum => "Inconsist CRC32 checksum",
             BamErrorKind::InconsistentBlockSize => "Actual size is different size",
-                    _ => "Unknown error"
+            BamErrorKind::OutputFailed => "Failed to write output",
         };
         write!(ft, "{}:{}: {}", self.line, self.function, msg)
     }
 }
 
+impl std::error::Error for BamHandleError {}
+
+impl BamHandleError {
+    pub fn kind(&self) -> BamErrorKind {
+        self.kind
+    }
+}
+
+/// Result of CRC32 validation of a BGZF block
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CrcStatus {
+    Unchecked,
+    Valid,
+    Mismatch,
+}
+
+/// A row of the corruption map, one per BGZF block
+#[derive(Debug, Clone)]
+pub struct BlockInfo {
+    pub offset:u64,    // file offset of the block header
+    pub bsize:usize,   // BSIZE field (total block size - 1)
+    pub isize:usize,   // ISIZE field
+    pub crc:CrcStatus,
+    pub error:Option<BamErrorKind>,
+    pub salvaged:usize, // bytes inflated from a failed block before the damage
+    pub tail:usize,     // bytes inflated from a failed block after the damage
+    pub repaired:usize, // bits flipped to repair the block
+    pub n_records:u64, // records starting in the block
+}
+
+impl BlockInfo {
+    pub fn new(offset:u64) -> BlockInfo {
+        BlockInfo{offset, bsize:0, isize:0, crc:CrcStatus::Unchecked, error:None, salvaged:0, tail:0, repaired:0, n_records:0}
+    }
+
+    /// True if only a part of the block was decoded
+    pub fn is_partial(&self) -> bool {
+        self.error.is_some() && self.repaired == 0
+    }
+}
+
+impl fmt::Display for CrcStatus {
+    fn fmt(&self, ft:&mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            CrcStatus::Unchecked => "unchecked",
+            CrcStatus::Valid => "ok",
+            CrcStatus::Mismatch => "mismatch",
+        };
+        write!(ft, "{}", msg)
+    }
+}
+
+/// Write corruption map as tab-separated values
+pub fn write_block_map_tsv(blocks:&[BlockInfo], output:&mut dyn Write) -> io::Result<()> {
+    writeln!(output, "offset\tbsize\tisize\tcrc\terror\tsalvaged\ttail\trepaired\tn_records")?;
+    for b in blocks {
+        let error = match b.error {
+            Some(e_) => format!("{:?}", e_),
+            None => ".".to_string(),
+        };
+        writeln!(output, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}", b.offset, b.bsize, b.isize, b.crc, error, b.salvaged, b.tail, b.repaired, b.n_records)?;
+    }
+    Ok(())
+}
+
+/// Write corruption map as an array of JSON objects
+pub fn write_block_map_json(blocks:&[BlockInfo], output:&mut dyn Write) -> io::Result<()> {
+    writeln!(output, "[")?;
+    for (i, b) in blocks.iter().enumerate() {
+        let error = match b.error {
+            Some(e_) => format!("\"{:?}\"", e_),
+            None => "null".to_string(),
+        };
+        writeln!(output, "  {{\"offset\":{},\"bsize\":{},\"isize\":{},\"crc\":\"{}\",\"error\":{},\"salvaged\":{},\"tail\":{},\"repaired\":{},\"n_records\":{}}}{}",
+            b.offset, b.bsize, b.isize, b.crc, error, b.salvaged, b.tail, b.repaired, b.n_records, if i + 1 < blocks.len() {","} else {""})?;
+    }
+    writeln!(output, "]")?;
+    Ok(())
+}
+
+/// Options of BGZF block decoding
+#[derive(Debug, Clone, Default)]
+pub struct BlockOptions {
+    pub salvage_partial:bool, // keep bytes inflated from a corrupted block
+    pub deflate_resync:bool,  // search deflate blocks after the damage of a corrupted block
+    pub repair_bits:usize,    // maximum number of bits flipped to repair a corrupted block
+    pub scan_chain:usize,     // number of headers chained by BSIZE to confirm a block found by scan
+}
+
 // Compress text into byte array
 // fn compress_text(text:&str)->Result<Vec<u8>,std::io::Error> {
 //     let mut buffer = Vec::new();
@@ -89,34 +183,33 @@ impl fmt::Display for BamHandleError {
 //     Ok(buffer)
 // }
 
-// A function to decompress byte array without gzip header using Decompress
-fn decompress_without_header(input: Vec<u8>) -> Result<Vec<u8>, std::io::Error> {
+// A function to decompress byte array without gzip header using Decompress.
+// Bytes inflated before an error remain in output.
+fn decompress_without_header(input:&[u8], output:&mut Vec<u8>) -> Result<(), std::io::Error> {
     // Create a new Decompress object with zlib_header set to false
     let mut decompress = Decompress::new(false);//_with_window_bits(false, 15);
 
-    // Create a vector to store the output
+    // Reserve the output vector
     let mut buffer_size = if input.len() < 256 { 1024 } else {input.len() * 4};
-    let mut output = Vec::<u8>::with_capacity(buffer_size);
+    output.reserve(buffer_size);
 
     // Decompress the input using Decompress and write it to the output vector
-    let mut status = decompress.decompress_vec(&input, &mut output, FlushDecompress::Finish)?;
+    let mut status = decompress.decompress_vec(input, output, FlushDecompress::Finish)?;
 
     while status != Status::StreamEnd { // if buffer size is less than expected
         buffer_size *= 2;
         if buffer_size > 65535 * 2 { // extracted buffer must be less than 0x100000000
             return Err(std::io::Error::new(ErrorKind::OutOfMemory, "probable corrupted block > 64kB"));
         }
-        let mut extended = Vec::<u8>::with_capacity(buffer_size);
+        output.reserve(buffer_size);
         status = decompress.decompress_vec(
             &input[decompress.total_in() as usize..], 
-            &mut extended,
+            output,
             FlushDecompress::Finish)?;
-        output.extend(extended);
     }
 
-    // Return the output vector
     output.shrink_to_fit();
-    Ok(output)
+    Ok(())
 }
 
 // fn decopress_with_window_bits(input:&[u8], shift:u8)->Result<Vec<u8>,std::io::Error> {
@@ -126,10 +219,113 @@ fn decompress_without_header(input: Vec<u8>) -> Result<Vec<u8>, std::io::Error>
 //     Ok(buf)
 // }
 
+// Tails shorter than this are rejected, since garbage bits sometimes inflate to a few bytes ending at the last byte
+const MIN_TAIL_SIZE:usize = 64;
+
+// Find the earliest bit offset of a deflate block from which the rest of raw deflate data inflates
+// to the end of stream. Back-references to the damaged part fail since the window is empty.
+fn recover_deflate_tail(input:&[u8], max_size:usize) -> Option<Vec<u8>> {
+    if input.len() < 2 {
+        return None;
+    }
+    // input shifted by 0-7 bits, data from bit offset b are shifted[b % 8][b / 8..]
+    let mut shifted:Vec<Vec<u8>> = vec![input.to_vec()];
+    for r in 1..8 {
+        let mut bytes = Vec::<u8>::with_capacity(input.len());
+        for i in 0..input.len() {
+            let next = if i + 1 < input.len() { input[i + 1] << (8 - r) } else { 0 };
+            bytes.push((input[i] >> r) | next);
+        }
+        shifted.push(bytes);
+    }
+
+    let mut decompress = Decompress::new(false);
+    let mut output = Vec::<u8>::with_capacity(max_size);
+    let mut stored = Vec::<u8>::with_capacity(input.len());
+    for bit in 1..(input.len() - 1) * 8 {
+        let mut data = &shifted[bit % 8][bit / 8..];
+        // BFINAL(1) BTYPE(2), stored block has LEN and one's complement NLEN at the next byte boundary
+        let btype = (data[0] >> 1) & 3;
+        if btype == 3 {
+            continue;
+        } else if btype == 0 {
+            // the stored block is moved to a byte boundary with the same header, so that data after it stay aligned
+            let aligned = (bit + 10) / 8;
+            if input.len() < aligned + 4 {
+                continue;
+            }
+            let len = LittleEndian::read_u16(&input[aligned..]);
+            let nlen = LittleEndian::read_u16(&input[aligned + 2..]);
+            if len != !nlen || aligned + 4 + len as usize > input.len() {
+                continue;
+            }
+            stored.clear();
+            stored.push(data[0] & 1);
+            stored.extend_from_slice(&input[aligned..]);
+            data = &stored;
+        }
+        decompress.reset(false);
+        output.clear();
+        if let Ok(Status::StreamEnd) = decompress.decompress_vec(data, &mut output, FlushDecompress::Finish) {
+            // stream must end at the last byte
+            if decompress.total_in() as usize + 1 >= data.len() && output.len() >= MIN_TAIL_SIZE && output.len() <= max_size {
+                #[cfg(debug_assertions)]
+                eprintln!("deflate block found at bit {} of {} bytes", bit, input.len());
+                return Some(output);
+            }
+        }
+    }
+    None
+}
+
+// Pairs of flipped bits are searched within REPAIR_DOUBLE_SPAN bits in REPAIR_DOUBLE_BYTES before the failure
+const REPAIR_DOUBLE_BYTES:usize = 256;
+const REPAIR_DOUBLE_SPAN:usize = 16;
+
+// Flip one bit, or two bits close to each other, of compressed data until inflated data agree with CRC32 and ISIZE.
+// Bits are tried backward from the position where inflation fails since the damage is usually close to it.
+// The end of data is used as the position if inflation succeeds.
+fn repair_block(cdata:&mut [u8], crc32_file:u32, input_size:usize, max_bits:usize) -> Option<(Vec<u8>, usize)> {
+    let mut decompress = Decompress::new(false);
+    let mut output = Vec::<u8>::with_capacity(input_size + 1);
+    // damage is located before the inflation error was detected, a few bytes are read ahead by the decoder
+    let n_bits = match decompress.decompress_vec(cdata, &mut output, FlushDecompress::Finish) {
+        Ok(Status::StreamEnd) => cdata.len() * 8,
+        _ => ((decompress.total_in() as usize + 8) * 8).min(cdata.len() * 8),
+    };
+    let double_start = n_bits.saturating_sub(REPAIR_DOUBLE_BYTES * 8);
+    let mut check = |data:&[u8], output:&mut Vec<u8>| -> bool {
+        decompress.reset(false);
+        output.clear();
+        match decompress.decompress_vec(data, output, FlushDecompress::Finish) {
+            Ok(Status::StreamEnd) => output.len() == input_size && calculate_crc32(output) == crc32_file,
+            _ => false,
+        }
+    };
+    for i in (0..n_bits).rev() {
+        cdata[i / 8] ^= 1 << (i % 8);
+        if check(cdata, &mut output) {
+            return Some((output, 1));
+        }
+        if max_bits >= 2 && i >= double_start {
+            for j in i + 1..(i + REPAIR_DOUBLE_SPAN).min(cdata.len() * 8) {
+                cdata[j / 8] ^= 1 << (j % 8);
+                let repaired = check(cdata, &mut output);
+                cdata[j / 8] ^= 1 << (j % 8);
+                if repaired {
+                    return Some((output, 2));
+                }
+            }
+        }
+        cdata[i / 8] ^= 1 << (i % 8);
+    }
+    None
+}
+
 // Calculate CRC32 checksum
-fn calculate_crc32(buffer:&Vec<u8>) -> u32 {
+fn calculate_crc32(buffer:&[u8]) -> u32 {
     let mut hasher = Hasher::new();
-    hasher.update(&buffer);
+    hasher.update(buffer);
     hasher.finalize()
 }
 
@@ -232,45 +428,153 @@ fn calculate_crc32(buffer:&Vec<u8>) -> u32 {
 //     Ok(buffer)
 // }
 
-// Read next data block without scan. This funtion outputs error immediately if the block is corrupted.
-fn read_next_block(reader: &mut BufReader<File>)->Result<Vec<u8>, BamHandleError> {
+/// Reader keeping the offset in the input stream, bytes pushed back are read again.
+/// Any reader including stdin and pipes can be used since the input is never seeked.
+const PEEK_SIZE:usize = 0x10000;
 
-    let mut buf:[u8;18] = [0;18];
-    let current_pos = reader.seek(SeekFrom::Current(0)).unwrap();
+pub struct BlockReader<R:Read> {
+    inner:R,
+    offset:u64,
+    pending:VecDeque<u8>, // bytes pushed back
+    consumed:Option<Vec<u8>>, // bytes consumed since the last take_consumed, kept for the quarantine of streams
+}
 
-    // Read first 18 bytes header
-    match reader.read_exact(&mut buf) {
-        Err(_err)=>return Err(
-            BamHandleError{line:line!(), function:function!().to_string(), kind:BamErrorKind::BufferTerminated}
-        ),
-        _ => (),
+impl<R:Read> BlockReader<R> {
+    pub fn new(inner:R) -> BlockReader<R> {
+        BlockReader{inner, offset:0, pending:VecDeque::new(), consumed:None}
     }
 
-    if buf[0..4] != [31,139,8,4] || buf[12..14] != [66,67] {
-        return Err(BamHandleError{line:line!(), function:function!().to_string(), kind:BamErrorKind::IncorrectGzipMagicNumber});
+    /// Offset of the next byte in the input stream
+    pub fn offset(&self) -> u64 {
+        self.offset
     }
 
-    let xlen = LittleEndian::read_u16(&buf[10..12]) as usize;
-    let block_size = LittleEndian::read_u16(&buf[16..18]) as usize;
-    // read extra xlen - 2 bytes
-    if xlen >= 6 && block_size > xlen + 19 {
-        let mut nullbuf:Vec<u8> = Vec::<u8>::with_capacity(xlen - 6); // skip extra fields
-        match reader.read_exact(&mut nullbuf) {
-            Err(_err)=>return Err(BamHandleError{line:line!(), function:function!().to_string(), kind:BamErrorKind::BufferTerminated}),
-            _ => (),
-        }
-        let compressed_data_size = block_size - xlen - 19;
-        let datablock = decompress_and_validate(reader, compressed_data_size)?;
-        Ok(datablock)
+    /// Keep consumed bytes, including bytes skipped by scan, to be taken by take_consumed
+    pub fn keep_consumed(&mut self) {
+        self.consumed = Some(Vec::new());
+    }
+
+    /// Bytes consumed since the last call, empty unless keep_consumed was called
+    pub fn take_consumed(&mut self) -> Vec<u8> {
+        self.consumed.as_mut().map_or(Vec::new(), std::mem::take)
+    }
+
+    /// Push back bytes to be read again
+    fn unread(&mut self, data:&[u8]) {
+        for b in data.iter().rev() {
+            self.pending.push_front(*b);
+        }
+        self.offset -= data.len() as u64;
+        if let Some(consumed_) = self.consumed.as_mut() {
+            consumed_.truncate(consumed_.len().saturating_sub(data.len()));
+        }
+    }
+
+    // Bytes following the current position without consuming them, shorter than size at the end of stream
+    fn peek(&mut self, size:usize) -> &[u8] {
+        while self.pending.len() < size {
+            let mut buf:Vec<u8> = vec![0; (size - self.pending.len()).max(PEEK_SIZE)];
+            match self.inner.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n_) => self.pending.extend(&buf[0..n_]),
+                Err(e_) if e_.kind() == ErrorKind::Interrupted => (),
+                Err(_) => break,
+            }
+        }
+        let available = size.min(self.pending.len());
+        &self.pending.make_contiguous()[0..available]
+    }
+
+    // Skip bytes already peeked
+    fn consume(&mut self, size:usize) {
+        let size = size.min(self.pending.len());
+        match self.consumed.as_mut() {
+            Some(consumed_) => consumed_.extend(self.pending.drain(0..size)),
+            None => { self.pending.drain(0..size); },
+        }
+        self.offset += size as u64;
+    }
+
+    // Read up to the size of buf, returns bytes read before the end of stream
+    fn read_available(&mut self, buf:&mut [u8]) -> usize {
+        let mut size = 0;
+        while size < buf.len() {
+            match self.read(&mut buf[size..]) {
+                Ok(0) => break,
+                Ok(n_) => size += n_,
+                Err(e_) if e_.kind() == ErrorKind::Interrupted => (),
+                Err(_) => break,
+            }
+        }
+        size
+    }
+}
+
+impl<R:Read> Read for BlockReader<R> {
+    fn read(&mut self, buf:&mut [u8]) -> io::Result<usize> {
+        let size = if !self.pending.is_empty() {
+            let size = buf.len().min(self.pending.len());
+            for (i, b) in self.pending.drain(0..size).enumerate() {
+                buf[i] = b;
+            }
+            size
+        } else {
+            self.inner.read(buf)?
+        };
+        if let Some(consumed_) = self.consumed.as_mut() {
+            consumed_.extend_from_slice(&buf[0..size]);
+        }
+        self.offset += size as u64;
+        Ok(size)
+    }
+}
+
+/// Compressed data and trailer of a BGZF block
+pub struct RawBlock {
+    pub cdata:Vec<u8>,
+    pub trailer:[u8;8], // CRC32 and ISIZE
+}
+
+/// Read next block. Headers are scanned after an error in framing blocks, regardless of decompression results,
+/// so that sequential and parallel decompression read the same blocks.
+pub fn frame_next_block<R:Read>(reader:&mut BlockReader<R>, scanmode:&mut bool, chain:usize, info:&mut BlockInfo)->Result<RawBlock, BamHandleError> {
+    let result = if *scanmode {
+        scan_next_block(reader, chain, info)
     } else {
-        eprintln!("Invalid block size AT {}", current_pos);            
-        Err(BamHandleError{line:line!(), function:function!().to_string(), kind:BamErrorKind::BlockCorrupted})
+        read_next_block(reader, info)
+    };
+    *scanmode = result.is_err();
+    result
+}
+
+// BSIZE in BC subfield of gzip extra field, None if the subfields are broken or BC is absent
+fn get_block_size(extra:&[u8]) -> Option<usize> {
+    // SI1   u8
+    // SI2   u8
+    // SLEN  u16
+    // data  u8[SLEN], BSIZE u16 if SI1/SI2 is BC
+    let mut block_size:Option<usize> = None;
+    let mut pos:usize = 0;
+    while pos + 4 <= extra.len() {
+        let slen = LittleEndian::read_u16(&extra[pos + 2..pos + 4]) as usize;
+        if pos + 4 + slen > extra.len() {
+            return None;
+        }
+        if extra[pos] == 66 && extra[pos + 1] == 67 && slen == 2 {
+            block_size = Some(LittleEndian::read_u16(&extra[pos + 4..pos + 6]) as usize);
+        }
+        pos += 4 + slen;
     }
+    if pos != extra.len() {
+        return None;
+    }
+    block_size
 }
 
-/// Scan header candidate position from file stream and read data block
-fn scan_next_block(reader:&mut BufReader<File>)->Result<Vec<u8>, BamHandleError> {
-    // ID1   0-0 u8 = 31 
+// Read gzip header and extra field of a block, BSIZE and XLEN are returned.
+// If the header is invalid, bytes following the first byte are pushed back to be scanned.
+fn read_block_header<R:Read>(reader:&mut BlockReader<R>, info:&mut BlockInfo)->Result<(usize, usize), BamHandleError> {
+    // ID1   0-0 u8 = 31
     // ID2   1-1 u8 = 139
     // CM    2-2 u8 = 8
     // FLG   3-3 u8 = 4
@@ -278,136 +582,225 @@ fn scan_next_block(reader:&mut BufReader<File>)->Result<Vec<u8>, BamHandleError>
     // XFL   8-8 u8
     // OS    9-9 u8
     // XLEN  10-11 u16
-    // SI1    | u8
-    // SI2    | u8
-    // SLEN   | u16
-    // BSIZE  | u16 12-12 + XLEN (min 6)
+    // extra subfields u8[XLEN], BC subfield gives BSIZE
     // CDATA u8[BSIZE-XLEN-19]
     // CRC32 u32
     // ISIZE u32
+    *info = BlockInfo::new(reader.offset());
+    let mut buf:Vec<u8> = vec![0;12];
+    let size = reader.read_available(&mut buf);
+    if size < 12 {
+        return Err(BamHandleError{line:line!(), function:function!().to_string(), kind:BamErrorKind::BufferTerminated});
+    }
+    if buf[0..4] != [31,139,8,4] {
+        reader.unread(&buf[1..12]);
+        return Err(BamHandleError{line:line!(), function:function!().to_string(), kind:BamErrorKind::IncorrectGzipMagicNumber});
+    }
+    let xlen = LittleEndian::read_u16(&buf[10..12]) as usize;
+    buf.resize(12 + xlen, 0);
+    let size = reader.read_available(&mut buf[12..]);
+    let block_size = if size == xlen { get_block_size(&buf[12..]) } else { None };
+    match block_size {
+        Some(bsize_) if bsize_ > xlen + 19 => {
+            info.bsize = bsize_;
+            Ok((bsize_, xlen))
+        },
+        _ => {
+            reader.unread(&buf[1..12 + size]);
+            Err(BamHandleError{line:line!(), function:function!().to_string(), kind:BamErrorKind::BlockCorrupted})
+        },
+    }
+}
 
-    let mut buf:[u8;18] = [0;18];
-    let mut xlen:usize = 0;
-    let mut block_size:usize = 0;
-    let current_pos = reader.seek(SeekFrom::Current(0)).unwrap();
+// Read next data block without scan. This funtion outputs error immediately if the block is corrupted.
+fn read_next_block<R:Read>(reader:&mut BlockReader<R>, info:&mut BlockInfo)->Result<RawBlock, BamHandleError> {
+    let (block_size, xlen) = match read_block_header(reader, info) {
+        Ok(h_) => h_,
+        Err(e_) => {
+            if e_.kind == BamErrorKind::BlockCorrupted {
+                eprintln!("Invalid block size AT {}", info.offset);
+            }
+            return Err(e_);
+        },
+    };
+    read_compressed_data(reader, block_size - xlen - 19, info)
+}
 
-    match reader.read_exact(&mut buf) {
-        Err(_err)=>return Err(BamHandleError{line:line!(), function:function!().to_string(), kind:BamErrorKind::BufferTerminated}),
-        _ => (),
+// BSIZE and XLEN of a valid block header found pos bytes ahead, the stream is not consumed
+fn peek_block_header<R:Read>(reader:&mut BlockReader<R>, pos:usize) -> Option<(usize, usize)> {
+    let head = reader.peek(pos + 12);
+    if head.len() < pos + 12 || head[pos..pos + 4] != [31,139,8,4] {
+        return None;
     }
-    loop { // scan
-        if buf[0..4] == [31,139,8,4] && buf[12..14] == [66,67] {
-            xlen = LittleEndian::read_u16(&buf[10..12]) as usize;
-            block_size = LittleEndian::read_u16(&buf[16..18]) as usize;
-            // read extra xlen - 2 bytes
-            if xlen >= 6 && block_size > xlen + 19 {
-                let mut nullbuf:Vec<u8> = Vec::<u8>::with_capacity(xlen - 6);
-                match reader.read_exact(&mut nullbuf) {
-                    Err(_err)=>return Err(BamHandleError{line:line!(), function:function!().to_string(), kind:BamErrorKind::BufferTerminated}),
-                    _ => (),
-                }
-                break;
-            }
-        }
+    let xlen = LittleEndian::read_u16(&head[pos + 10..pos + 12]) as usize;
+    let head = reader.peek(pos + 12 + xlen);
+    if head.len() < pos + 12 + xlen {
+        return None;
+    }
+    match get_block_size(&head[pos + 12..]) {
+        Some(bsize_) if bsize_ > xlen + 19 => Some((bsize_, xlen)),
+        _ => None,
+    }
+}
 
-        let mut shift_bytes:usize = 18;
-        for i in 1..18 {
-            if buf[i] == 31 {
-                shift_bytes = i;
-                for j in 0..18-i {
-                    buf[j] = buf[i+j];
-                }
-                shift_bytes = i;
-                break;
-            }
+// Test whether valid headers follow a block of block_size at the current position, up to depth blocks.
+// The end of stream right after a block is also accepted.
+fn confirm_chain<R:Read>(reader:&mut BlockReader<R>, block_size:usize, depth:usize) -> bool {
+    let mut next = block_size + 1;
+    for _ in 0..depth {
+        if reader.peek(next + 1).len() == next {
+            return true;
         }
-        match reader.read_exact(&mut buf[18-shift_bytes..18]) {
-            Err(_err)=>return Err(BamHandleError{line:line!(), function:function!().to_string(), kind:BamErrorKind::BufferTerminated}),
-            _ => (),
+        match peek_block_header(reader, next) {
+            Some((bsize_, _)) => next += bsize_ + 1,
+            None => return false,
         }
     }
+    true
+}
 
-    if xlen >= 6 && block_size > xlen + 19 {
-        let skip_bytes = xlen - 6;
-        let mut filler:Vec<u8> = Vec::<u8>::with_capacity(skip_bytes);
-        // let mut filler:[u8;skip_bytes] = [0;skip_bytes];
-        // let mut filler[u8:skip_bytes] = u8[0;skip_bytes];
-        match reader.read_exact(&mut filler) {
-            Err(_err)=>return Err(BamHandleError{line:line!(), function:function!().to_string(), kind:BamErrorKind::BufferTerminated}),
-            _=>(),
-        }
+// Test whether a block at the current position inflates into ISIZE bytes, the stream is not consumed
+fn inflate_candidate<R:Read>(reader:&mut BlockReader<R>, block_size:usize, xlen:usize) -> bool {
+    let data = reader.peek(block_size + 1);
+    if data.len() < block_size + 1 {
+        return false;
     }
+    let input_size = LittleEndian::read_u32(&data[block_size - 3..block_size + 1]) as usize;
+    let mut buffer:Vec<u8> = Vec::new();
+    input_size <= 0x10000 && decompress_without_header(&data[12 + xlen..block_size - 7], &mut buffer).is_ok() && buffer.len() == input_size
+}
 
-    let compressed_data_size = block_size - xlen - 19;
-    let datablock = decompress_and_validate(reader, compressed_data_size)?;
-    Ok(datablock)
-
+/// Scan header candidate position from file stream and read data block.
+/// Candidates are confirmed by valid headers chained by BSIZE up to chain blocks ahead,
+/// otherwise they are accepted only if they inflate consistently with ISIZE.
+fn scan_next_block<R:Read>(reader:&mut BlockReader<R>, chain:usize, info:&mut BlockInfo)->Result<RawBlock, BamHandleError> {
+    loop { // scan
+        let head = reader.peek(PEEK_SIZE);
+        if head.is_empty() {
+            return Err(BamHandleError{line:line!(), function:function!().to_string(), kind:BamErrorKind::BufferTerminated});
+        }
+        match head.iter().position(|b_| *b_ == 31) {
+            Some(pos_) => reader.consume(pos_),
+            None => { // no candidate
+                let size = head.len();
+                reader.consume(size);
+                continue;
+            },
+        }
+        {
+            if let Some((block_size_, xlen_)) = peek_block_header(reader, 0) {
+                if confirm_chain(reader, block_size_, chain) || inflate_candidate(reader, block_size_, xlen_) {
+                    read_block_header(reader, info)?;
+                    return read_compressed_data(reader, block_size_ - xlen_ - 19, info);
+                }
+                #[cfg(debug_assertions)]
+                eprintln!("block candidate rejected AT {}", reader.offset());
+            }
+        }
+        reader.consume(1); // continue from the next byte
+    }
 }
 
-fn decompress_and_validate(reader:&mut BufReader<File>, datasize:usize)->Result<Vec<u8>, BamHandleError> {
+// Read compressed data, CRC32 and ISIZE
+fn read_compressed_data<R:Read>(reader:&mut BlockReader<R>, datasize:usize, info:&mut BlockInfo)->Result<RawBlock, BamHandleError> {
     // println!("xlen = {}, subfield length = {}, block size = {}", xlen, subfield_length, block_size);
     // let compressed_data_size = block_size - xlen - 19;
-    let current_pos = reader.seek(SeekFrom::Current(0)).unwrap();
-    let mut cdata = vec![0u8;datasize as usize];
-    match reader.read_exact(&mut cdata) {
-        Err(_err)=>return Err(
-            BamHandleError{line:line!(), function:function!().to_string(), kind:BamErrorKind::BufferTerminated}
-        ),
-        _ => (),
+    let mut cdata = vec![0u8;datasize];
+    if reader.read_exact(&mut cdata).is_err() {
+        return Err(BamHandleError{line:line!(), function:function!().to_string(), kind:BamErrorKind::BufferTerminated});
     }
     // read CRC32 and expected size
     let mut tbuf:[u8;8] = [0;8];
-    match reader.read_exact(&mut tbuf) {
-        Err(_err)=>return Err(
-            BamHandleError{line:line!(), function:function!().to_string(), kind:BamErrorKind::BufferTerminated}
-        ),
-        _ => (),
+    if reader.read_exact(&mut tbuf).is_err() {
+        return Err(BamHandleError{line:line!(), function:function!().to_string(), kind:BamErrorKind::BufferTerminated});
     }
+    info.isize = LittleEndian::read_u32(&tbuf[4..8]) as usize;
+    Ok(RawBlock{cdata, trailer:tbuf})
+}
+
+/// Inflate a block and validate CRC32 and ISIZE. Failed blocks are repaired or salvaged according to options.
+pub fn decompress_and_validate(block:RawBlock, options:&BlockOptions, info:&mut BlockInfo)->Result<Vec<u8>, BamHandleError> {
+    let mut cdata = block.cdata;
+    let tbuf = block.trailer;
+    let current_pos = info.offset;
 
     // Gzip decompression
     let mut buffer:Vec<u8> = Vec::<u8>::new();
-    let input_size = cdata.len();
-    match decompress_without_header(cdata) {
-        Ok(b_)=>buffer=b_, 
+    let crc32_file = LittleEndian::read_u32(&tbuf[0..4]);
+    let input_size = LittleEndian::read_u32(&tbuf[4..8]) as usize;
+    let mut crc32_calc:u32 = 0;
+    let mut inflated = false;
+    let kind = match decompress_without_header(&cdata, &mut buffer) {
         Err(e_) => {
             eprintln!("{:?} AT {}", e_, current_pos);
-            return Err(
-                BamHandleError{line:line!(), function:function!().to_string(), kind:BamErrorKind::BlockCorrupted}
-                )
-            },
+            BamErrorKind::BlockCorrupted
+        },
+        Ok(_) => { // Data validation
+            crc32_calc = calculate_crc32(&buffer);
+            inflated = true;
+            info.crc = if crc32_calc == crc32_file { CrcStatus::Valid } else { CrcStatus::Mismatch };
+            if input_size != buffer.len() {
+                eprintln!("Inconsist : ({} -> {} bytes) AT {}", input_size, buffer.len(), current_pos);
+                BamErrorKind::InconsistentBlockSize
+            } else if crc32_calc != crc32_file {
+                eprintln!("CRC32 : {:X} <=> {:X} AT {}", crc32_calc, crc32_file, current_pos);
+                BamErrorKind::InconsistentChecksum
+            } else {
+                // println!("{}:{} {} bytes, CRC32={:x}", function!().to_string(), line!(), buffer.len(), crc32_file);
+                return Ok(buffer);
+            }
+        },
+    };
+
+    // Repair by flipping bits of the trailer or the compressed data
+    if options.repair_bits > 0 {
+        let n_bits_crc = (crc32_calc ^ crc32_file).count_ones() as usize;
+        let n_bits_size = (input_size ^ buffer.len()).count_ones() as usize;
+        if inflated && n_bits_size == 0 && n_bits_crc <= options.repair_bits {
+            eprintln!("CRC32 field repaired by {} bit(s) AT {}", n_bits_crc, current_pos);
+            info.repaired = n_bits_crc;
+        } else if inflated && n_bits_crc == 0 && n_bits_size <= options.repair_bits {
+            eprintln!("ISIZE field repaired by {} bit(s) AT {}", n_bits_size, current_pos);
+            info.repaired = n_bits_size;
+        } else {
+            if let Some((repaired_, n_bits_)) = repair_block(&mut cdata, crc32_file, input_size, options.repair_bits) {
+                eprintln!("compressed data repaired by {} bit(s) AT {}", n_bits_, current_pos);
+                buffer = repaired_;
+                info.repaired = n_bits_;
+                info.crc = CrcStatus::Valid;
+            }
+        }
+        if info.repaired > 0 {
+            info.error = Some(kind);
+            return Ok(buffer);
+        }
     }
 
-    // Data validation
-    let input_size = LittleEndian::read_u32(&tbuf[4..8]) as usize;
-    if input_size != buffer.len() {
-        eprintln!("Inconsist : ({} -> {} bytes) AT {}", input_size, buffer.len(), current_pos);
-        return Err(
-            BamHandleError{
-                line:line!(), function:function!().to_string(), kind:BamErrorKind::InconsistentBlockSize
-            });
-    } 
-
-    let crc32_calc = calculate_crc32(&buffer);
-    let crc32_file = LittleEndian::read_u32(&tbuf[0..4]);
-    if crc32_calc != crc32_file {
-        eprintln!("CRC32 : {:X} <=> {:X} AT {}", crc32_calc, crc32_file, current_pos);
-        return Err(
-            BamHandleError{
-                line:line!(), function:function!().to_string(), kind:BamErrorKind::InconsistentChecksum
-            });
+    // Bytes inflated before the error and bytes inflated from a later deflate block are passed to the caller
+    // with the error recorded in info
+    if !options.salvage_partial {
+        buffer.clear();
+    }
+    info.salvaged = buffer.len();
+    if options.deflate_resync && kind == BamErrorKind::BlockCorrupted {
+        if let Some(mut tail_) = recover_deflate_tail(&cdata, input_size.min(65536)) {
+            eprintln!("{} bytes recovered from later deflate block AT {}", tail_.len(), current_pos);
+            info.tail = tail_.len();
+            buffer.append(&mut tail_);
+        }
+    }
+    if !buffer.is_empty() {
+        info.error = Some(kind);
+        return Ok(buffer);
     }
-    // println!("{}:{} {} bytes, CRC32={:x}", function!().to_string(), line!(), buffer.len(), crc32_file);
-    Ok(buffer)
+    Err(BamHandleError{line:line!(), function:function!().to_string(), kind})
 }
 
 // Convert 2-nuc encoded byte array into sequence
-fn convert_sequence(buffer:&Vec<u8>, start:usize, length:usize) -> String {
+pub(crate) fn convert_sequence(buffer:&[u8], start:usize, length:usize) -> String {
     static BASES:[u8;16] = [61, 65, 67, 77, 71, 82, 83, 86, 84, 87, 89, 72, 75, 68, 66, 78];
-    let mut seq:Vec<u8> = Vec::<u8>::with_capacity(length + 1);
-    unsafe {
-        seq.set_len(length + 1);
-    }
-    let span = ((length + 1) / 2);
+    let mut seq:Vec<u8> = vec![0u8;length + 1];
+    let span = length.div_ceil(2);
 
     for i in 0..span {
         if i + start >= buffer.len() {
@@ -420,18 +813,23 @@ fn convert_sequence(buffer:&Vec<u8>, start:usize, length:usize) -> String {
         seq[i * 2] = BASES[b0 as usize];
         seq[i * 2 + 1] = BASES[b1 as usize];
     }
-    unsafe {
-        seq.set_len(length);
-    }
+    seq.truncate(length);
     String::from_utf8(seq).unwrap()
 }
 
+// Reverse complement of IUPAC sequence in the letters of convert_sequence
+pub(crate) fn reverse_complement(seq:&str) -> String {
+    seq.bytes().rev().map(|b_| match b_ {
+        b'A' => 'T', b'C' => 'G', b'G' => 'C', b'T' => 'A',
+        b'M' => 'K', b'K' => 'M', b'R' => 'Y', b'Y' => 'R',
+        b'V' => 'B', b'B' => 'V', b'H' => 'D', b'D' => 'H',
+        _ => b_ as char, // =, S, W and N
+    }).collect()
+}
+
 // Convert QUAL values into string
-fn convert_qual(buffer:&Vec<u8>, start:usize, length:usize) -> String {
-    let mut seq:Vec<u8> = Vec::<u8>::with_capacity(length);
-    unsafe {
-        seq.set_len(length);
-    }
+pub(crate) fn convert_qual(buffer:&[u8], start:usize, length:usize) -> String {
+    let mut seq:Vec<u8> = vec![0u8;length];
 
     for i in 0..length {
         let b:u8 = buffer[i + start];
@@ -443,237 +841,919 @@ fn convert_qual(buffer:&Vec<u8>, start:usize, length:usize) -> String {
     String::from_utf8(seq).unwrap_or("?".to_string())
 }
 
-// dumping hex
-fn get_hex_string(buffer:&Vec<u8>, pos:usize, span:usize) -> String {
-    let mut hexstr:String = String::new();
-    for i in 0..span {
-        hexstr = [hexstr, format!("{:02X}", buffer[pos + i - span / 2])].join(" ");
-    }    
-    return hexstr;
+// Upper bound of block_size accepted for a single alignment record (SEQ + QUAL of a few hundred Mb)
+const MAX_RECORD_SIZE:usize = 1 << 30;
+
+/// Result of testing whether an alignment record begins at a given offset of decompressed data
+#[derive(Debug, PartialEq)]
+pub(crate) enum RecordCheck {
+    /// All available fields are consistent, record size including block_size field
+    Plausible(usize),
+    /// Fixed fields, read name or CIGAR are not available yet
+    Incomplete,
+    Invalid,
 }
 
-pub fn retrieve_fastq(filename_bam:&String, output:&mut Box<dyn Write>, info:HashMap<&str,i32>)
-    ->Result<HashMap<String,String>, BamHandleError> {
-    let mut results:HashMap<String,String> = HashMap::new();
-    let mut n_seqs:u64 = 0;
-    let mut n_bases:u64 = 0;
-    let mut n_blocks:u64 = 0;
-    let mut n_corrupted_blocks:u64 = 0;
-    let mut verbose:bool = false;
-    let mut noqual:bool = false;
-    let mut limit:u64 = 0;
-
-    // println!("{:?}", info.get("verbose"));
-    // eprintln!("input={}", filename_bam);
-    for (key, val) in info {
-        // eprintln!("{} = {}", key, val);
-        if key == "verbose" && val > 0 {
-            verbose = true;
-        } else if key == "noqual" && val > 0 {
-            noqual = true;
-        } else if key == "limit" {
-            limit = if val < 0 { 0 } else { val as u64 };
-        }
-    }
-    if verbose {
-        eprintln!("processing {}", filename_bam);
-    }
-
-    // output.write("hello".as_bytes());
-
-    // read header
-    // fn read_next_block(handler:&mut BufReader)->Result<Vec<u8>, Box<error::Error>> {
-    let file_in = std::fs::File::open(filename_bam).unwrap();
-    let filesize =
-    match file_in.metadata() {
-        Ok(m_) => m_.len(),
-        Err(e_) => {
-            return Err(BamHandleError{line:line!(), function:function!().to_string(), kind:BamErrorKind::NoBAMFile});
-        },
+/// Result of scanning decompressed data for the next record start
+#[derive(Debug, PartialEq)]
+pub(crate) enum RecordScan {
+    Found(usize),
+    /// No record start before the offset, more data are required to test the offset
+    NeedMore(usize),
+}
+
+// Test consistency of a record at pos. Fields located beyond the buffer are not tested.
+pub(crate) fn check_record(buffer:&[u8], pos:usize, n_ref:Option<usize>) -> RecordCheck {
+    if buffer.len() < pos + 36 {
+        return RecordCheck::Incomplete;
+    }
+    let rec = &buffer[pos..];
+    let block_size = LittleEndian::read_u32(&rec[0..4]) as usize;
+    let ref_id = LittleEndian::read_i32(&rec[4..8]);
+    let ref_pos = LittleEndian::read_i32(&rec[8..12]);
+    let l_read_name = rec[12] as usize;
+    let n_cigar_op = LittleEndian::read_u16(&rec[16..18]) as usize;
+    let flag = LittleEndian::read_u16(&rec[18..20]);
+    let l_seq = LittleEndian::read_u32(&rec[20..24]) as usize;
+    let next_ref_id = LittleEndian::read_i32(&rec[24..28]);
+    let next_pos = LittleEndian::read_i32(&rec[28..32]);
+
+    // coordinates
+    let max_ref_id = match n_ref {
+        Some(n_) => n_ as i64,
+        None => i32::MAX as i64,
     };
+    if ref_id < -1 || ref_id as i64 >= max_ref_id || next_ref_id < -1 || next_ref_id as i64 >= max_ref_id
+        || ref_pos < -1 || next_pos < -1 || flag >= 0x1000 {
+        return RecordCheck::Invalid;
+    }
 
-    let mut reader:BufReader<File> = BufReader::new(file_in);
-    let mut buffer:Vec<u8> = Vec::new();
+    // sizes of variable-length fields must fit into block_size
+    let seq_ptr = 36 + l_read_name + n_cigar_op * 4;
+    let qual_ptr = seq_ptr + l_seq.div_ceil(2);
+    let aux_ptr = qual_ptr + l_seq;
+    if l_read_name < 2 || block_size > MAX_RECORD_SIZE || aux_ptr > block_size + 4 {
+        return RecordCheck::Invalid;
+    }
+    if rec.len() < seq_ptr {
+        return RecordCheck::Incomplete;
+    }
 
-    // header, if the data block is corrupted, skip the part 
-    buffer = scan_next_block(&mut reader)?;
-    // Assert BAM\1
-    if buffer[0..4] != [66, 65, 77, 1] {
-        return Err(BamHandleError{line:line!(), function:function!().to_string(), kind:BamErrorKind::IncorrectMagicNumber});
-    }
-    let l_text = LittleEndian::read_u32(&buffer[4..8]) as usize;
-    let l_buffer = buffer.len() as usize;
-    let mut scanmode:bool = false;
-    buffer.clear();
-
-/////////////////////////////////////////
-    // reader.seek(SeekFrom::Start(13520742501));//SeekFrom::Start(filesize * 18 / 100));
-
-    loop {
-        // read a block (from 0)
-        if scanmode {
-            match scan_next_block(&mut reader) {
-                Ok(mut _data) => {
-                    scanmode = false;
-                    buffer = _data;
-                },
-                Err(_err) => { 
-                    #[cfg(debug_assertions)]
-                    eprintln!("no BGZF block found : {}", _err); 
-                    break;
-                },
-            }
-        } else {
-            match read_next_block(&mut reader) { // fill buffer until the end of block
-                Ok(mut _data) => {if buffer.len() == 0 {buffer=_data} else {buffer.append(&mut _data)}},
-                Err(_err) => {
-                    #[cfg(debug_assertions)]
-                    {
-                        let current_pos = reader.seek(SeekFrom::Current(0)).unwrap();
-                        eprintln!("corrupted block detected at {}.", current_pos);
-                    }
-                    // eprintln!("{}", _err); 
-                    n_corrupted_blocks += 1;
-                    scanmode = true;
-                    continue;
-                },
+    // read_name is [!-?A-~]+ terminated by NUL
+    if rec[35 + l_read_name] != 0 {
+        return RecordCheck::Invalid;
+    }
+    for &c in &rec[36..35 + l_read_name] {
+        if !(33..=126).contains(&c) || c == 64 {
+            return RecordCheck::Invalid;
+        }
+    }
+
+    // CIGAR operations are MIDNSHP=X and consume l_seq bases of query
+    let mut query_length:usize = 0;
+    for i in 0..n_cigar_op {
+        let op = LittleEndian::read_u32(&rec[36 + l_read_name + i * 4..40 + l_read_name + i * 4]);
+        match op & 0x0f {
+            0 | 1 | 4 | 7 | 8 => query_length += (op >> 4) as usize,
+            2 | 3 | 5 | 6 => (),
+            _ => return RecordCheck::Invalid,
+        }
+    }
+    if n_cigar_op > 0 && l_seq > 0 && query_length != l_seq {
+        return RecordCheck::Invalid;
+    }
+
+    // '=' is meaningless in unmapped reads
+    let seq_end = qual_ptr.min(rec.len());
+    if flag & 4 != 0 {
+        for (i, &b) in rec.iter().enumerate().take(seq_end).skip(seq_ptr) {
+            if b >> 4 == 0 || (b & 0x0f == 0 && (i + 1 < qual_ptr || l_seq.is_multiple_of(2))) {
+                return RecordCheck::Invalid;
             }
         }
-        n_blocks += 1;
-
-        // read fields 
-        while buffer.len() >= 36 {
-            // block_size u32  0-3
-            // refID i32       4-7
-            // pos i32         8-11
-            // l_read_name u8  12
-            // mapq u8         13
-            // bin u16         14-15
-            // n_cigar_op u16  16-17
-            // flag u16        18-19
-            // l_seq u32       20-23
-            // next_refID i32  24-27
-            // next_pos i32    28-31
-            // tlen i32        32-35
-            // read_name u8 * l_read_name +36
-            // cigar u32 * n_cigar_op
-            // seq u8 * (l_seq+1)/2
-            // qual char[l_seq]
-            let block_size = LittleEndian::read_u32(&buffer[0..4]) as usize;
-            let l_read_name = buffer[12] as usize;
-            let l_seq = LittleEndian::read_u32(&buffer[20..24]) as usize;
-            let tlen = LittleEndian::read_i32(&buffer[32..36]);
-            let ptr_block_start = 4;
-            let drain_pos = ptr_block_start + block_size;
-            let n_cigar_op = LittleEndian::read_u16(&buffer[16..18]) as usize;
-
-            let seq_ptr = l_read_name + 36 + n_cigar_op * 4;
-            let minimum_buffer_size = seq_ptr + l_seq * (if noqual {1} else {3}) / 2;
-            if drain_pos <= 36 || drain_pos < minimum_buffer_size { // bad drain position
-                n_corrupted_blocks += 1;
-                scanmode = true;
-            } else {
-                // fill buffer
-                while drain_pos > buffer.len() || buffer.len() < minimum_buffer_size {
-                    let prevsize = buffer.len();
-                    match read_next_block(&mut reader) {
-                        Ok(mut _data)=>{
-                            buffer.append(&mut _data);
-                            n_blocks += 1;
-                        },
-                        Err(e_)=>{
-                            let current_pos = reader.seek(SeekFrom::Current(0)).unwrap();
-                            #[cfg(debug_assertions)]
-                            eprintln!("{}:corrupted block detected at {}   ", line!(), current_pos);
-                            scanmode = true;
-                            break;
-                        },
-                    }
-                }
+    }
+
+    // QUAL is either Phred scores up to 93 or 0xFF filled
+    let qual_end = aux_ptr.min(rec.len());
+    if qual_ptr < qual_end {
+        if rec[qual_ptr] == 0xff {
+            if rec[qual_ptr..qual_end].iter().any(|&q| q != 0xff) {
+                return RecordCheck::Invalid;
             }
-            if scanmode || drain_pos < 36 || buffer.len() < 36 || l_read_name < 3 {
-                buffer.clear();
-                n_corrupted_blocks += 1;
-                break;
+        } else if rec[qual_ptr..qual_end].iter().any(|&q| q > 93) {
+            return RecordCheck::Invalid;
+        }
+    }
+
+    // auxiliary data begin with a tag name and a known type
+    if aux_ptr < block_size + 4 {
+        if block_size + 4 - aux_ptr < 4 {
+            return RecordCheck::Invalid;
+        }
+        if rec.len() >= aux_ptr + 3 {
+            let tag = &rec[aux_ptr..aux_ptr + 3];
+            if !tag[0].is_ascii_alphabetic() || !tag[1].is_ascii_alphanumeric() || !b"AcCsSiIfZHB".contains(&tag[2]) {
+                return RecordCheck::Invalid;
             }
-            // std::process::exit(0);
-            // process sequence (and qual) even if block corrupted
-            // eprintln!("drain_pos={}, buffer_size={}, minimum={}\n", drain_pos, buffer.len(), minimum_buffer_size);
-
-            // eprintln!("{} at {}", function!().to_string(), line!());
-            if drain_pos <= buffer.len() { // sequence contained in the block
-                // eprintln!("\n{} {}\n", buffer.len(), l_read_name);
-                let seq_name = str::from_utf8(&buffer[36..35+l_read_name]).unwrap();
-
-                if seq_ptr + minimum_buffer_size > buffer.len() { // overflow
-                    #[cfg(debug_assertions)]
-                    eprintln!("sequence position overflow the block minimal_size={}/buffer_size={}",
-                        minimum_buffer_size, buffer.len());
-                    n_corrupted_blocks += 1;
-                    scanmode = true;
-                    break;
-                }
-                // println!("{}\t{}\t{}", seq_name, l_seq, n_cigar_op);
-                // skip CIGAR and read SEQ and QUAL
-                let sequence = convert_sequence(&buffer, seq_ptr, l_seq);
-                let mut seq_display:String;
-                if l_seq != sequence.len() {
-                    #[cfg(debug_assertions)]
-                    eprintln!("{} had bad SEQ", seq_name);
-                    n_corrupted_blocks += 1;
-                    scanmode = true;
-                    break;
-                } else if l_seq > 40 {
-                    seq_display = format!("{}..{}", &sequence[0..20], &sequence[l_seq-20..]);
-                } else if l_seq > 20 {
-                    seq_display = format!("{}..", &sequence[0..20]);
-                } else {
-                    seq_display = sequence.clone();
+        }
+    }
+    RecordCheck::Plausible(block_size + 4)
+}
+
+// Find the next plausible record start at or after from.
+// A candidate followed by another record is accepted only if the following record is plausible too.
+pub(crate) fn find_record_start(buffer:&[u8], from:usize, n_ref:Option<usize>) -> RecordScan {
+    let mut pos = from;
+    while pos + 36 <= buffer.len() {
+        match check_record(buffer, pos, n_ref) {
+            RecordCheck::Plausible(size_) => {
+                match check_record(buffer, pos + size_, n_ref) {
+                    RecordCheck::Invalid => (),
+                    _ => return RecordScan::Found(pos),
                 }
+            },
+            RecordCheck::Incomplete => return RecordScan::NeedMore(pos),
+            RecordCheck::Invalid => (),
+        }
+        pos += 1;
+    }
+    RecordScan::NeedMore(pos)
+}
 
-                if noqual {
-                    output.write(format!(">{}\n{}\n", seq_name, sequence).as_bytes());
-                    // println!("{}\t{}\t{}", seq_name, l_seq, seq_display);
-                } else {
-                    let qual = convert_qual(&buffer, seq_ptr + (l_seq + 1)/ 2, l_seq);
-                    let mut qual_display:String;
-                    if qual.len() != l_seq { // invalid character in qual string
-                        #[cfg(debug_assertions)]
-                        eprintln!("{} had bad QUAL", seq_name);
-                        n_corrupted_blocks += 1;
-                        scanmode = true;
-                        break;
-                    } else if l_seq > 40 {
-                        qual_display = format!("{}..{}", &qual[0..20], &qual[l_seq-20..]);
-                    } else if l_seq > 20 {
-                        qual_display = format!("{}..", &qual[0..20]);
-                    } else {
-                        qual_display = qual.clone();
-                    }
-                    output.write(format!("@{}\n{}\n+\n{}\n", seq_name, sequence, qual).as_bytes());
-                    // println!("{}\t{}\t{}\t{}", seq_name, l_seq, seq_display, qual_display);
+// Records on references missing from the output header, such as those of a lost header, are written as unplaced
+// with refID, pos, next_refID and next_pos of -1, since such records are rejected by samtools
+fn unplace_record(data:&mut [u8], n_ref:i32) {
+    if LittleEndian::read_i32(&data[4..8]) >= n_ref {
+        LittleEndian::write_i32(&mut data[4..8], -1);
+        LittleEndian::write_i32(&mut data[8..12], -1);
+        LittleEndian::write_u16(&mut data[14..16], 4680); // bin of unplaced records
+        LittleEndian::write_i32(&mut data[32..36], 0);
+    }
+    if LittleEndian::read_i32(&data[24..28]) >= n_ref {
+        LittleEndian::write_i32(&mut data[24..28], -1);
+        LittleEndian::write_i32(&mut data[28..32], -1);
+        LittleEndian::write_i32(&mut data[32..36], 0);
+    }
+}
+
+// Error of writing outputs in salvage
+fn output_failed(line:u32) -> BamHandleError {
+    BamHandleError{line, function:"salvage".to_string(), kind:BamErrorKind::OutputFailed}
+}
+
+/// Write reads taken from the records according to the settings
+// FASTA or FASTQ record, None if QUAL is invalid
+fn format_text(settings:&Salvager, record:&SalvagedRecord, fields:&[AuxField], mods:ModCheck) -> Option<String> {
+    // reads ending in the salvaged part of a corrupted block are marked
+    let mut comment = if record.partial { "\tZS:Z:partial".to_string() } else { String::new() };
+    // MM and ML refer to the orientation of the sequencer, converted if reads are written as stored
+    let stored = if mods == ModCheck::Valid && !settings.original_orientation && record.is_reverse() {
+        stored_modifications(record, fields)
+    } else {
+        None
+    };
+    // selected tags in SAM format as samtools fastq -T, modification tags inconsistent with SEQ are dropped
+    for tag in &settings.tags {
+        let field = match fields.iter().find(|f_| &f_.tag == tag) {
+            Some(f_) => f_,
+            None => continue,
+        };
+        if mods == ModCheck::Invalid && (tag == "MM" || tag == "ML" || tag == "MN" || tag == "ZM") {
+            continue;
+        }
+        let text = match (tag.as_str(), stored.as_ref()) {
+            ("MM", Some((mm_, _))) => format!("MM:Z:{}", mm_),
+            ("ML", Some((_, ml_))) => AuxField{tag:"ML".to_string(), value:AuxValue::IntArray(b'C', ml_.clone())}.to_string(),
+            _ => field.to_string(),
+        };
+        comment += &format!("\t{}", text);
+    }
+    if mods == ModCheck::Invalid {
+        comment += "\tZM:Z:invalid";
+    }
+    // block offset, offset in the decompressed block and resync before the record
+    if settings.provenance_tags {
+        comment += &format!("\tZO:i:{}\tZU:i:{}\tZR:i:{}", record.block_offset(), record.offset_in_block(), record.after_resync as u8);
+    }
+    // reverse-strand reads are written in the orientation of the sequencer
+    let reverse = settings.original_orientation && record.is_reverse();
+    let seq = if reverse { reverse_complement(&record.seq()) } else { record.seq() };
+    if settings.format == OutputFormat::Fasta || (!record.has_qual() && settings.missing_qual == MissingQual::Fasta) {
+        return Some(format!(">{}{}\n{}\n", record.name(), comment, seq));
+    }
+    let qual = if !record.has_qual() { // QUAL is filled with 0xFF
+        let placeholder = match settings.missing_qual {
+            MissingQual::Placeholder(q_) => (q_ + 33) as char,
+            MissingQual::Fasta => unreachable!(),
+        };
+        std::iter::repeat_n(placeholder, record.l_seq()).collect()
+    } else {
+        convert_qual(record.qual(), 0, record.l_seq())
+    };
+    if qual.len() != record.l_seq() { // invalid QUAL
+        #[cfg(debug_assertions)]
+        eprintln!("{} had bad QUAL", record.name());
+        return None;
+    }
+    let qual:String = if reverse { qual.chars().rev().collect() } else { qual };
+    Some(format!("@{}{}\n{}\n+\n{}\n", record.name(), comment, seq, qual))
+}
+
+// SAM line of a record, references are named by the dictionary of the header
+fn format_sam(record:&SalvagedRecord, header:&BamHeader, fields:&[AuxField], mods:ModCheck) -> String {
+    let reference = |ref_id_:i32| -> &str {
+        if ref_id_ < 0 {
+            return "*";
+        }
+        header.references.get(ref_id_ as usize).map_or("*", |r_| r_.name.as_str())
+    };
+    // records on references missing from the header are unplaced as in BAM output
+    let n_ref = header.references.len() as i32;
+    let (ref_id, pos) = if record.ref_id() < n_ref { (record.ref_id(), record.pos()) } else { (-1, -1) };
+    let (next_ref_id, next_pos) = if record.next_ref_id() < n_ref { (record.next_ref_id(), record.next_pos()) } else { (-1, -1) };
+    let tlen = if ref_id == record.ref_id() && next_ref_id == record.next_ref_id() { record.tlen() } else { 0 };
+    let cigar:String = record.cigar().iter().map(|op_| format!("{}{}", op_ >> 4, "MIDNSHP=X".as_bytes()[(op_ & 0x0f) as usize] as char)).collect();
+    let next_reference = if next_ref_id >= 0 && next_ref_id == ref_id { "=" } else { reference(next_ref_id) };
+    let qual = if record.has_qual() { convert_qual(record.qual(), 0, record.l_seq()) } else { String::new() };
+    let mut line = format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+        record.name(), record.flag(), reference(ref_id), pos + 1, record.mapq(),
+        if cigar.is_empty() { "*" } else { &cigar }, next_reference, next_pos + 1, tlen,
+        if record.l_seq() == 0 { "*".to_string() } else { record.seq() }, if qual.is_empty() { "*" } else { &qual });
+    for field in fields {
+        line += &format!("\t{}", field);
+    }
+    // reads ending in the salvaged part of a corrupted block are marked as in BAM output
+    if record.partial {
+        line += "\tZS:Z:partial";
+    }
+    if mods == ModCheck::Invalid && !fields.iter().any(|f_| f_.tag == "ZM") {
+        line += "\tZM:Z:invalid";
+    }
+    line.push('\n');
+    line
+}
+
+pub(crate) fn salvage(settings:&Salvager, mut records:RecordIterator, output:&mut dyn Write, paired:Option<PairedOutputs>) -> Result<SalvageReport, BamHandleError> {
+    let mut n_seqs:u64 = 0;
+    let mut n_bases:u64 = 0;
+    let mut n_partial_seqs:u64 = 0;
+    let mut n_missing_qual:u64 = 0;
+    let mut n_invalid_mods:u64 = 0;
+    let mut n_filtered_required:u64 = 0;
+    let mut n_filtered_excluded:u64 = 0;
+    let mut reached_limit = false;
+    let bam_output = settings.format == OutputFormat::Bam;
+    let sam_output = settings.format == OutputFormat::Sam;
+    let limit = settings.limit;
+    let exclude_flags = settings.excluded_flags();
+
+    // BAM output starts with the recovered header
+    let mut bgzf:Option<BgzfWriter<&mut dyn Write>> = None;
+    let mut output:&mut dyn Write = if bam_output { bgzf.insert(BgzfWriter::with_threads(output, Compression::default(), settings.threads)) } else { output };
+    if bam_output {
+        match records.header() {
+            Some(header_) => {
+                output.write_all(&header_.to_bytes()).map_err(|_| output_failed(line!()))?;
+            },
+            None => {
+                eprintln!("header is not recovered, records are written as unplaced");
+                output.write_all(&BamHeader::default().to_bytes()).map_err(|_| output_failed(line!()))?;
+            }
+        }
+        output.flush().map_err(|_| output_failed(line!()))?; // records start from a new block as htslib does
+    }
+    // SAM output starts with the recovered header and a @PG line of bamsalvage
+    let header = records.header().cloned().unwrap_or_default();
+    let n_ref = header.references.len() as i32;
+    if sam_output {
+        let mut header = header.clone();
+        header.add_program("bamsalvage", env!("CARGO_PKG_VERSION"));
+        output.write_all(header.to_sam().as_bytes()).map_err(|_| output_failed(line!()))?;
+    }
+    // origin of written reads
+    let mut provenance:Option<BufWriter<File>> = match settings.provenance_tsv.as_ref() {
+        Some(filename_) => {
+            let mut writer = match File::create(filename_) {
+                Ok(f_) => BufWriter::new(f_),
+                Err(_) => return Err(output_failed(line!())),
+            };
+            writeln!(writer, "name\tflag\tblock_offset\toffset_in_block\tvirtual_offset\tafter_resync\tpartial").map_err(|_| output_failed(line!()))?;
+            Some(writer)
+        },
+        None => None,
+    };
+    // mates are split into files in FASTA/FASTQ
+    let mut paired = if bam_output || sam_output { None } else { paired.map(|p_| PairedWriter::new(p_, settings.mate_buffer)) };
+
+    for item in records.by_ref() {
+        let record = match item {
+            Ok(r_) => r_,
+            Err(_event) => {
+                #[cfg(debug_assertions)]
+                eprintln!("{:?}", _event);
+                continue;
+            },
+        };
+        // flag filters, -f then -F of samtools
+        if record.flag() & settings.require_flags != settings.require_flags {
+            n_filtered_required += 1;
+            continue;
+        }
+        if record.flag() & exclude_flags != 0 {
+            n_filtered_excluded += 1;
+            continue;
+        }
+        let fields = record.tags();
+        let mods = check_modifications(&record, &fields);
+        if mods == ModCheck::Invalid {
+            n_invalid_mods += 1;
+        }
+        if !bam_output && !record.has_qual() {
+            n_missing_qual += 1;
+        }
+        if bam_output { // records are copied without conversion
+            let unplaced = record.ref_id() >= n_ref || record.next_ref_id() >= n_ref;
+            if record.partial || mods == ModCheck::Invalid || unplaced {
+                let mut data = record.raw().to_vec();
+                if unplaced {
+                    unplace_record(&mut data, n_ref);
                 }
-                n_seqs += 1;
-                n_bases += sequence.len() as u64;
-
-                if verbose && n_seqs % 1000 == 0 {
-                    let current_pos = reader.seek(SeekFrom::Current(0)).unwrap();
-                    eprint!("\x1B {:.1}% {}k reads / {}k blocks / {} corrupted  {}\r", 
-                        current_pos as f32 * 100.0 / (filesize as f32),
-                        n_seqs / 1000, n_blocks / 1000, n_corrupted_blocks, seq_name)
+                if record.partial {
+                    data.extend_from_slice(b"ZSZpartial\0");
                 }
-                buffer.drain(0..drain_pos);
-            } // process a read
+                if mods == ModCheck::Invalid && !fields.iter().any(|f_| f_.tag == "ZM") { // not flagged yet
+                    data.extend_from_slice(b"ZMZinvalid\0");
+                }
+                let block_size = data.len() as u32 - 4;
+                LittleEndian::write_u32(&mut data[0..4], block_size);
+                output.write_all(&data).map_err(|_| output_failed(line!()))?;
+            } else {
+                output.write_all(record.raw()).map_err(|_| output_failed(line!()))?;
+            }
+        } else if sam_output {
+            output.write_all(format_sam(&record, &header, &fields, mods).as_bytes()).map_err(|_| output_failed(line!()))?;
+        } else {
+            let text = match format_text(settings, &record, &fields, mods) {
+                Some(t_) => t_,
+                None => continue,
+            };
+            let written = match paired.as_mut() {
+                Some(paired_) => paired_.write(record.flag(), record.name(), text.as_bytes(), &mut output),
+                None => output.write_all(text.as_bytes()),
+            };
+            written.map_err(|_| output_failed(line!()))?;
+        }
+        if let Some(writer_) = provenance.as_mut() {
+            writeln!(writer_, "{}\t{}\t{}\t{}\t{}\t{}\t{}", record.name(), record.flag(), record.block_offset(), record.offset_in_block(),
+                record.virtual_offset, record.after_resync as u8, record.partial as u8).map_err(|_| output_failed(line!()))?;
+        }
+        n_seqs += 1;
+        n_bases += record.l_seq() as u64;
+        if record.partial { // the record ends in the salvaged part of a corrupted block
+            n_partial_seqs += 1;
         }
         if limit > 0 && n_seqs >= limit {
+            reached_limit = true;
             break;
         }
     }
+    if let Some(paired_) = paired.as_mut() {
+        paired_.finish(!reached_limit, &mut output).map_err(|_| output_failed(line!()))?;
+    }
+    if let Some(writer_) = provenance.as_mut() {
+        writer_.flush().map_err(|_| output_failed(line!()))?;
+    }
+    output.flush().map_err(|_| output_failed(line!()))?;
+    // the EOF block of BAM
+    if let Some(writer_) = bgzf.as_mut() {
+        writer_.finish().map_err(|_| output_failed(line!()))?;
+    }
+
+    let mut report = records.report();
+    report.n_sequences = n_seqs;
+    report.n_bases = n_bases;
+    report.n_partial_sequences = n_partial_seqs;
+    report.n_missing_qual = n_missing_qual;
+    report.n_invalid_modifications = n_invalid_mods;
+    report.n_filtered_required = n_filtered_required;
+    report.n_filtered_excluded = n_filtered_excluded;
+    if let Some(paired_) = paired {
+        report.n_pairs = paired_.n_pairs;
+        report.n_orphans = paired_.n_orphans;
+        report.n_unknown_pairs = paired_.n_unknown;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use flate2::{Compress, FlushCompress};
+    use crate::bgzf::{BgzfWriter, BGZF_BLOCK_DATA_SIZE};
 
-    results.insert("filesize".to_string(), format!("{}", filesize).to_string());
-    results.insert("n_sequences".to_string(), format!("{}", n_seqs).to_string());
-    results.insert("n_bases".to_string(), format!("{}", n_bases).to_string());
-    results.insert("n_blocks".to_string(), format!("{}", n_blocks).to_string());
-    results.insert("n_corrupted".to_string(), format!("{}", n_corrupted_blocks).to_string());
-    Ok(results)
-}
\ No newline at end of file
+    // An unmapped-like record with the given CIGAR, SEQ of A and QUAL
+    fn make_record(name:&str, flag:u16, cigar:&[u32], qual:&[u8]) -> Vec<u8> {
+        let l_seq = qual.len();
+        let mut rec:Vec<u8> = Vec::new();
+        rec.extend_from_slice(&[0; 4]); // block_size
+        rec.extend_from_slice(&0i32.to_le_bytes());
+        rec.extend_from_slice(&100i32.to_le_bytes());
+        rec.push(name.len() as u8 + 1);
+        rec.push(60);
+        rec.extend_from_slice(&0u16.to_le_bytes());
+        rec.extend_from_slice(&(cigar.len() as u16).to_le_bytes());
+        rec.extend_from_slice(&flag.to_le_bytes());
+        rec.extend_from_slice(&(l_seq as u32).to_le_bytes());
+        rec.extend_from_slice(&(-1i32).to_le_bytes());
+        rec.extend_from_slice(&(-1i32).to_le_bytes());
+        rec.extend_from_slice(&0i32.to_le_bytes());
+        rec.extend_from_slice(name.as_bytes());
+        rec.push(0);
+        for op in cigar {
+            rec.extend_from_slice(&op.to_le_bytes());
+        }
+        rec.resize(rec.len() + l_seq.div_ceil(2), 0x11);
+        rec.extend_from_slice(qual);
+        rec.extend_from_slice(b"NMC\x00");
+        let block_size = rec.len() as u32 - 4;
+        rec[0..4].copy_from_slice(&block_size.to_le_bytes());
+        rec
+    }
+
+    // Pack bits into bytes from the least significant bit as deflate
+    fn pack_bits(bits:&[bool]) -> Vec<u8> {
+        let mut bytes = vec![0u8; bits.len().div_ceil(8)];
+        for (i, b) in bits.iter().enumerate() {
+            if *b {
+                bytes[i / 8] |= 1 << (i % 8);
+            }
+        }
+        bytes
+    }
+
+    // Stored blocks after k garbage bits
+    fn stored_blocks(k:usize, blocks:&[(bool, &[u8])]) -> Vec<u8> {
+        let mut bits:Vec<bool> = vec![true; k];
+        for (bfinal, data) in blocks {
+            bits.extend_from_slice(&[*bfinal, false, false]);
+            while !bits.len().is_multiple_of(8) {
+                bits.push(false);
+            }
+            let len = data.len() as u16;
+            for byte in len.to_le_bytes().iter().chain((!len).to_le_bytes().iter()).chain(data.iter()) {
+                bits.extend((0..8).map(|i_| byte & (1 << i_) != 0));
+            }
+        }
+        pack_bits(&bits)
+    }
+
+    fn dna(length:usize, seed:u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..length).map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            b"ACGT"[(state >> 16) as usize % 4]
+        }).collect()
+    }
+
+    #[test]
+    fn unaligned_stored_block() {
+        let data = dna(200, 1);
+        for k in 1..8 {
+            let input = stored_blocks(k, &[(true, &data)]);
+            assert_eq!(recover_deflate_tail(&input, 65536), Some(data.clone()), "{} bits", k);
+        }
+        // inflation continues after a stored block that is not final
+        let next = dna(100, 2);
+        for k in 1..8 {
+            let input = stored_blocks(k, &[(false, &data), (true, &next)]);
+            assert_eq!(recover_deflate_tail(&input, 65536), Some([data.clone(), next.clone()].concat()), "{} bits", k);
+        }
+    }
+
+    #[test]
+    fn no_tail_in_single_block() {
+        let data = dna(6000, 3);
+        let mut compress = Compress::new(Compression::default(), false);
+        let mut cdata = Vec::<u8>::with_capacity(data.len() * 2);
+        assert_eq!(compress.compress_vec(&data, &mut cdata, FlushCompress::Finish).unwrap(), Status::StreamEnd);
+        for pos in (cdata.len() / 5..cdata.len() * 4 / 5).step_by(37) {
+            let mut damaged = cdata.clone();
+            damaged[pos] ^= 0x24;
+            assert_eq!(recover_deflate_tail(&damaged, data.len()), None, "flip at {}", pos);
+        }
+    }
+
+    #[test]
+    fn valid_record() {
+        let rec = make_record("read1", 0, &[10 << 4], &[30; 10]);
+        assert_eq!(check_record(&rec, 0, Some(1)), RecordCheck::Plausible(rec.len()));
+        assert_eq!(check_record(&rec, 0, None), RecordCheck::Plausible(rec.len()));
+        // refID out of the references
+        assert_eq!(check_record(&rec, 0, Some(0)), RecordCheck::Invalid);
+    }
+
+    #[test]
+    fn truncated_record() {
+        let rec = make_record("read1", 0, &[10 << 4], &[30; 10]);
+        assert_eq!(check_record(&rec[..30], 0, None), RecordCheck::Incomplete);
+        assert_eq!(check_record(&rec[..40], 0, None), RecordCheck::Incomplete);
+        assert_eq!(find_record_start(&rec[..40], 0, None), RecordScan::NeedMore(0));
+        // block_size too small for SEQ and QUAL
+        let mut rec = rec;
+        rec[0..4].copy_from_slice(&40u32.to_le_bytes());
+        assert_eq!(check_record(&rec, 0, None), RecordCheck::Invalid);
+    }
+
+    #[test]
+    fn bad_cigar() {
+        let rec = make_record("read1", 0, &[(10 << 4) | 9], &[30; 10]);
+        assert_eq!(check_record(&rec, 0, None), RecordCheck::Invalid);
+        // CIGAR not consuming l_seq bases
+        let rec = make_record("read1", 0, &[(5 << 4) | 2, 9 << 4], &[30; 10]);
+        assert_eq!(check_record(&rec, 0, None), RecordCheck::Invalid);
+    }
+
+    #[test]
+    fn missing_qual() {
+        let rec = make_record("read1", 4, &[], &[0xff; 11]);
+        assert_eq!(check_record(&rec, 0, None), RecordCheck::Plausible(rec.len()));
+        let mut qual = vec![0xff; 11];
+        qual[5] = 30;
+        let rec = make_record("read1", 4, &[], &qual);
+        assert_eq!(check_record(&rec, 0, None), RecordCheck::Invalid);
+    }
+
+    #[test]
+    fn resync_in_garbage() {
+        let mut data:Vec<u8> = (0..200u32).map(|i_| (i_.wrapping_mul(2654435761) >> 13) as u8 | 0x80).collect();
+        let start = data.len();
+        let rec1 = make_record("read1", 0, &[10 << 4], &[30; 10]);
+        let rec2 = make_record("read2", 16, &[4 << 4, (2 << 4) | 1, 4 << 4], &[20; 10]);
+        data.extend_from_slice(&rec1);
+        data.extend_from_slice(&rec2);
+        assert_eq!(find_record_start(&data, 0, Some(1)), RecordScan::Found(start));
+        assert_eq!(find_record_start(&data, start + 1, Some(1)), RecordScan::Found(start + rec1.len()));
+    }
+
+    #[test]
+    fn block_size_in_extra() {
+        assert_eq!(get_block_size(&[66, 67, 2, 0, 0x1b, 0x00]), Some(27));
+        // BC after another subfield
+        assert_eq!(get_block_size(&[65, 66, 3, 0, 1, 2, 3, 66, 67, 2, 0, 0xff, 0xff]), Some(65535));
+        assert_eq!(get_block_size(&[65, 66, 3, 0, 1, 2, 3]), None);
+        // subfield running over XLEN
+        assert_eq!(get_block_size(&[66, 67, 4, 0, 0x1b, 0x00]), None);
+        // bytes left after the last subfield
+        assert_eq!(get_block_size(&[66, 67, 2, 0, 0x1b, 0x00, 0]), None);
+    }
+
+    #[test]
+    fn repair_flipped_bits() {
+        let data = dna(5000, 4);
+        let mut compress = Compress::new(Compression::default(), false);
+        let mut cdata = Vec::<u8>::with_capacity(data.len() * 2);
+        compress.compress_vec(&data, &mut cdata, FlushCompress::Finish).unwrap();
+        let crc32 = calculate_crc32(&data);
+        let pos = cdata.len() / 2;
+
+        let mut damaged = cdata.clone();
+        damaged[pos] ^= 0x10;
+        assert_eq!(repair_block(&mut damaged, crc32, data.len(), 1), Some((data.clone(), 1)));
+        assert_eq!(damaged, cdata); // the repaired data are left
+
+        // pairs are searched close to the position where inflation fails
+        let pos = cdata.len() - 100;
+        damaged[pos] ^= 0x30;
+        assert_eq!(repair_block(&mut damaged, crc32, data.len(), 1), None);
+        assert_eq!(damaged[pos], cdata[pos] ^ 0x30); // flipped bits are restored after the search
+        assert_eq!(repair_block(&mut damaged, crc32, data.len(), 2), Some((data.clone(), 2)));
+    }
+
+    // BAM data of n records in BGZF, a byte of compressed data is altered at each fraction of the size in damaged
+    fn make_bam(n:usize, damaged:&[f64]) -> Vec<u8> {
+        let header = BamHeader::from_text("@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:100000\n");
+        let mut bam:Vec<u8> = Vec::new();
+        let mut writer = BgzfWriter::new(&mut bam);
+        writer.write_all(&header.to_bytes()).unwrap();
+        writer.flush().unwrap();
+        for i in 0..n {
+            let qual:Vec<u8> = (0..50 + i % 100).map(|j_| (j_ % 40) as u8).collect();
+            writer.write_all(&make_record(&format!("read{}", i), 0, &[(qual.len() as u32) << 4], &qual)).unwrap();
+        }
+        writer.finish().unwrap();
+        drop(writer);
+        for fraction in damaged {
+            let pos = (bam.len() as f64 * fraction) as usize;
+            bam[pos] ^= 0x55;
+        }
+        bam
+    }
+
+    #[test]
+    fn sequential_and_parallel_output() {
+        let bam = make_bam(5000, &[0.3, 0.6]);
+        let salvage_with = |n_threads:usize| {
+            let mut output:Vec<u8> = Vec::new();
+            let report = Salvager::new("-").threads(n_threads).partial(true).deflate_resync(true)
+                .run_reader(Cursor::new(bam.clone()), &mut output).unwrap();
+            (output, report)
+        };
+        let (sequential, report) = salvage_with(1);
+        assert!(report.n_sequences > 0 && report.n_sequences < 5000);
+        assert!(report.n_corrupted_blocks > 0);
+        for n_threads in [2, 4] {
+            let (parallel, report_) = salvage_with(n_threads);
+            assert!(sequential == parallel, "{} threads", n_threads);
+            assert_eq!(report_.n_sequences, report.n_sequences);
+            assert_eq!(report_.corrupted_ranges, report.corrupted_ranges);
+        }
+    }
+
+    #[test]
+    fn report_of_partial_header_block() {
+        // the header block alone without the EOF block
+        let bam = make_bam(0, &[]);
+        let size = LittleEndian::read_u16(&bam[16..18]) as usize + 1;
+        let mut single = bam[..size].to_vec();
+        single[size / 2] ^= 0x08;
+        let report = Salvager::new("-").partial(true).run_reader(Cursor::new(single), &mut Vec::<u8>::new()).unwrap();
+        assert_eq!((report.n_blocks, report.n_partial_blocks), (1, 1));
+        let mut json:Vec<u8> = Vec::new();
+        report.write_json(&mut json).unwrap();
+        assert!(String::from_utf8(json).unwrap().contains("\"blocks\":{\"total\":1,\"ok\":0,\"corrupted\":1,\"partial\":1,"));
+    }
+
+    #[test]
+    fn quarantine_of_stream() {
+        let bam = make_bam(5000, &[0.5]);
+        let dir = std::env::temp_dir().join(format!("bamsalvage_quarantine_{}", std::process::id()));
+        let report = Salvager::new("-").quarantine(dir.to_str().unwrap())
+            .run_reader(Cursor::new(bam.clone()), &mut Vec::<u8>::new()).unwrap();
+        let index = std::fs::read_to_string(dir.join("index.tsv")).unwrap();
+        let ranges:Vec<(u64, u64)> = index.lines().skip(1).map(|l_| l_.split('\t').collect::<Vec<&str>>())
+            .filter(|items| items[1] == "corrupted").map(|items| {
+            let (start, end) = (items[2].parse::<u64>().unwrap(), items[3].parse::<u64>().unwrap());
+            assert_eq!(std::fs::read(dir.join(items[0])).unwrap(), &bam[start as usize..end as usize]);
+            (start, end)
+        }).collect();
+        // ranges are renamed with their ends when the damage ends
+        let n_files = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(!ranges.is_empty());
+        assert_eq!(ranges, report.corrupted_ranges);
+        assert_eq!(n_files, index.lines().count());
+    }
+
+    #[test]
+    fn damage_to_end_of_stream() {
+        let bam = make_bam(5000, &[]);
+        let mut offsets:Vec<usize> = Vec::new();
+        let mut offset = 0;
+        while offset < bam.len() {
+            offsets.push(offset);
+            offset += u16::from_le_bytes([bam[offset + 16], bam[offset + 17]]) as usize + 1;
+        }
+        // the stream ends with a damaged block
+        let (start, end) = (offsets[offsets.len() - 3], offsets[offsets.len() - 2]);
+        let mut cut = bam[0..end].to_vec();
+        for b_ in cut[start + 100..start + 104].iter_mut() {
+            *b_ ^= 0xff;
+        }
+        let dir = std::env::temp_dir().join(format!("bamsalvage_truncated_{}", std::process::id()));
+        let report = Salvager::new("-").quarantine(dir.to_str().unwrap())
+            .run_reader(Cursor::new(cut.clone()), &mut Vec::<u8>::new()).unwrap();
+        let index = std::fs::read_to_string(dir.join("index.tsv")).unwrap();
+        let name = format!("corrupted_{}_{}.bin", start, cut.len());
+        assert!(index.contains(&name));
+        assert_eq!(std::fs::read(dir.join(&name)).unwrap(), &cut[start..]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(report.filesize, cut.len() as u64);
+        assert_eq!(report.corrupted_ranges, vec![(start as u64, cut.len() as u64)]);
+        assert_eq!(report.bytes_lost(), (end - start) as u64);
+    }
+
+    #[test]
+    fn incomplete_candidate_at_end() {
+        // a record start claiming CIGAR operations beyond the end of the stream
+        let mut fake = make_record("fake", 0, &[], &[]);
+        fake[0..4].copy_from_slice(&100000u32.to_le_bytes());
+        fake[16..18].copy_from_slice(&2000u16.to_le_bytes());
+        fake.truncate(36);
+        let header = BamHeader::from_text("@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:100000\n");
+        let mut bam:Vec<u8> = Vec::new();
+        let mut writer = BgzfWriter::new(&mut bam);
+        writer.write_all(&header.to_bytes()).unwrap();
+        writer.write_all(&fake).unwrap();
+        for i in 0..10 {
+            writer.write_all(&make_record(&format!("read{}", i), 0, &[10 << 4], &[30; 10])).unwrap();
+        }
+        writer.finish().unwrap();
+        drop(writer);
+        let mut records = Salvager::new("-").records_from_reader(Cursor::new(bam)).unwrap();
+        let salvaged:Vec<SalvagedRecord> = records.by_ref().filter_map(|r_| r_.ok()).collect();
+        assert_eq!(salvaged.len(), 10);
+        assert_eq!(records.report().n_resync, 1);
+    }
+
+    // BAM data of a header with many references, records follow the header without flushing its last block
+    fn make_large_header_bam(n_ref:usize, n:usize) -> (BamHeader, Vec<u8>) {
+        let text:String = std::iter::once("@HD\tVN:1.6\n".to_string())
+            .chain((0..n_ref).map(|i_| format!("@SQ\tSN:contig{}\tLN:{}\n", i_, 1000 + i_))).collect();
+        let header = BamHeader::from_text(&text);
+        let mut bam:Vec<u8> = Vec::new();
+        let mut writer = BgzfWriter::new(&mut bam);
+        writer.write_all(&header.to_bytes()).unwrap();
+        for i in 0..n {
+            writer.write_all(&make_record(&format!("read{}", i), 0, &[10 << 4], &[30; 10])).unwrap();
+        }
+        writer.finish().unwrap();
+        drop(writer);
+        (header, bam)
+    }
+
+    #[test]
+    fn header_across_blocks() {
+        let (header, bam) = make_large_header_bam(3000, 10);
+        let header_size = header.to_bytes().len();
+        assert!(header_size > 2 * BGZF_BLOCK_DATA_SIZE);
+        // offset of the last block of the header
+        let mut offset = 0;
+        for _ in 0..header_size / BGZF_BLOCK_DATA_SIZE {
+            offset += LittleEndian::read_u16(&bam[offset + 16..offset + 18]) as usize + 1;
+        }
+
+        let mut records = Salvager::new("-").records_from_reader(Cursor::new(bam)).unwrap();
+        assert_eq!(records.header(), Some(&header));
+        let salvaged:Vec<SalvagedRecord> = records.by_ref().filter_map(|r_| r_.ok()).collect();
+        assert_eq!(salvaged.len(), 10);
+        assert_eq!(salvaged[0].name(), "read0");
+        assert_eq!(salvaged[0].block_offset(), offset as u64);
+        assert_eq!(salvaged[0].offset_in_block(), (header_size % BGZF_BLOCK_DATA_SIZE) as u64);
+        let report = records.report();
+        assert_eq!((report.n_resync, report.n_corrupted_blocks), (0, 0));
+    }
+
+    #[test]
+    fn header_with_lost_block() {
+        let (_, mut bam) = make_large_header_bam(3000, 10);
+        // the second block of the header is damaged
+        let offset = LittleEndian::read_u16(&bam[16..18]) as usize + 1;
+        bam[offset + 1000] ^= 0x55;
+        let mut records = Salvager::new("-").records_from_reader(Cursor::new(bam)).unwrap();
+        assert_eq!(records.header(), None);
+        let salvaged:Vec<SalvagedRecord> = records.by_ref().filter_map(|r_| r_.ok()).collect();
+        assert_eq!(salvaged.iter().map(|r_| r_.name()).collect::<Vec<&str>>(), (0..10).map(|i_| format!("read{}", i_)).collect::<Vec<String>>());
+        assert_eq!(records.report().n_corrupted_blocks, 1);
+    }
+
+    // make_bam with the header block damaged
+    fn make_lost_header_bam(n:usize) -> Vec<u8> {
+        let mut bam = make_bam(n, &[]);
+        bam[20] ^= 0x55;
+        bam
+    }
+
+    #[test]
+    fn headerless_salvage() {
+        let bam = make_lost_header_bam(100);
+        assert!(Salvager::new("-").run_reader(Cursor::new(bam.clone()), &mut Vec::<u8>::new()).is_err());
+        let mut output:Vec<u8> = Vec::new();
+        let report = Salvager::new("-").headerless(true).format(OutputFormat::Sam)
+            .run_reader(Cursor::new(bam), &mut output).unwrap();
+        assert_eq!(report.n_sequences, 100);
+        // the reference of records is unknown
+        let text = String::from_utf8(output).unwrap();
+        let fields:Vec<&str> = text.lines().find(|l_| !l_.starts_with('@')).unwrap().split('\t').collect();
+        assert_eq!(fields[0], "read0");
+        assert_eq!(&fields[2..4], &["*", "0"]);
+        assert_eq!(&fields[6..9], &["*", "0", "0"]);
+    }
+
+    #[test]
+    fn sibling_header() {
+        let bam = make_lost_header_bam(100);
+        // records on a reference missing from the sibling header are kept as unplaced
+        let sibling = BamHeader::from_text("@HD\tVN:1.6\n@CO\tsibling\n");
+        let mut output:Vec<u8> = Vec::new();
+        let report = Salvager::new("-").header(sibling.clone()).format(OutputFormat::Bam)
+            .run_reader(Cursor::new(bam), &mut output).unwrap();
+        assert_eq!(report.n_sequences, 100);
+        let mut records = Salvager::new("-").records_from_reader(Cursor::new(output)).unwrap();
+        assert_eq!(records.header().unwrap().comments, sibling.comments);
+        let salvaged:Vec<SalvagedRecord> = records.by_ref().filter_map(|r_| r_.ok()).collect();
+        assert_eq!(salvaged.len(), 100);
+        assert!(salvaged.iter().all(|r_| (r_.ref_id(), r_.pos(), r_.next_ref_id()) == (-1, -1, -1)));
+    }
+
+    // BAM data of the given records after a header of chr1
+    fn bam_of(records:&[Vec<u8>]) -> Vec<u8> {
+        let header = BamHeader::from_text("@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:100000\n");
+        let mut bam:Vec<u8> = Vec::new();
+        let mut writer = BgzfWriter::new(&mut bam);
+        writer.write_all(&header.to_bytes()).unwrap();
+        writer.flush().unwrap();
+        for rec in records {
+            writer.write_all(rec).unwrap();
+        }
+        writer.finish().unwrap();
+        drop(writer);
+        bam
+    }
+
+    #[test]
+    fn default_flag_exclusion() {
+        let bam = bam_of(&[0, 0x100, 0x800, 0x10].iter().enumerate()
+            .map(|(i_, f_)| make_record(&format!("read{}", i_), *f_, &[10 << 4], &[30; 10])).collect::<Vec<Vec<u8>>>());
+        let names = |salvager:Salvager| {
+            let mut output:Vec<u8> = Vec::new();
+            salvager.run_reader(Cursor::new(bam.clone()), &mut output).unwrap();
+            if salvager.format == OutputFormat::Bam {
+                let records = Salvager::new("-").records_from_reader(Cursor::new(output)).unwrap();
+                return records.filter_map(|r_| r_.ok()).map(|r_| r_.name().to_string()).collect::<Vec<String>>();
+            }
+            let text = String::from_utf8(output).unwrap();
+            match salvager.format {
+                OutputFormat::Sam => text.lines().filter(|l_| !l_.starts_with('@')).map(|l_| l_.split('\t').next().unwrap().to_string()).collect(),
+                _ => text.lines().step_by(4).map(|l_| l_[1..].to_string()).collect(),
+            }
+        };
+        // secondary and supplementary alignments are skipped in reads, kept in alignments
+        assert_eq!(names(Salvager::new("-")), ["read0", "read3"]);
+        assert_eq!(names(Salvager::new("-").format(OutputFormat::Sam)), ["read0", "read1", "read2", "read3"]);
+        assert_eq!(names(Salvager::new("-").format(OutputFormat::Bam)), ["read0", "read1", "read2", "read3"]);
+        // flags given are applied to any format
+        assert_eq!(names(Salvager::new("-").format(OutputFormat::Bam).exclude_flags(0x10)), ["read0", "read1", "read2"]);
+        assert_eq!(names(Salvager::new("-").exclude_flags(0)), ["read0", "read1", "read2", "read3"]);
+        assert_eq!(names(Salvager::new("-").format(OutputFormat::Sam).require_flags(0x100)), ["read1"]);
+    }
+
+    #[test]
+    fn sam_output() {
+        let bam = bam_of(&[make_record("read0", 0, &[10 << 4], &[30; 10]),
+            make_record("read1", 16, &[4 << 4, (2 << 4) | 1, 4 << 4], &[20; 10])]);
+        let mut output:Vec<u8> = Vec::new();
+        Salvager::new("-").format(OutputFormat::Sam).run_reader(Cursor::new(bam), &mut output).unwrap();
+        let text = String::from_utf8(output).unwrap();
+        let lines:Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[..2], ["@HD\tVN:1.6", "@SQ\tSN:chr1\tLN:100000"]);
+        assert_eq!(lines[2], format!("@PG\tID:bamsalvage\tPN:bamsalvage\tVN:{}", env!("CARGO_PKG_VERSION")));
+        assert_eq!(lines[3], "read0\t0\tchr1\t101\t60\t10M\t*\t0\t0\tAAAAAAAAAA\t??????????\tNM:i:0");
+        // reverse-strand reads are written as stored
+        assert_eq!(lines[4], "read1\t16\tchr1\t101\t60\t4M2I4M\t*\t0\t0\tAAAAAAAAAA\t5555555555\tNM:i:0");
+    }
+
+    #[test]
+    fn provenance_of_tail() {
+        let records:Vec<Vec<u8>> = (0..6).map(|i_| {
+            let qual:Vec<u8> = (0..100).map(|j_| ((i_ * 7 + j_ * 3) % 40) as u8).collect();
+            make_record(&format!("read{}", i_), 0, &[100 << 4], &qual)
+        }).collect();
+        // a block of records 0-3 in a compressed deflate block followed by records 4 and 5 in a stored block
+        let head = records[..4].concat();
+        let tail = records[4..].concat();
+        let mut compress = Compress::new(Compression::default(), false);
+        let mut cdata = Vec::<u8>::with_capacity(head.len() * 2);
+        compress.compress_vec(&head, &mut cdata, FlushCompress::Full).unwrap();
+        let pos = cdata.len() / 3;
+        cdata[pos] ^= 0x55;
+        cdata.push(1);
+        cdata.extend_from_slice(&(tail.len() as u16).to_le_bytes());
+        cdata.extend_from_slice(&(!(tail.len() as u16)).to_le_bytes());
+        cdata.extend_from_slice(&tail);
+        let data = [head.clone(), tail.clone()].concat();
+        let mut block:Vec<u8> = vec![31, 139, 8, 4, 0, 0, 0, 0, 0, 255, 6, 0, 66, 67, 2, 0];
+        block.extend_from_slice(&(cdata.len() as u16 + 25).to_le_bytes());
+        block.extend_from_slice(&cdata);
+        block.extend_from_slice(&calculate_crc32(&data).to_le_bytes());
+        block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+
+        let mut bam = bam_of(&[]);
+        let offset = LittleEndian::read_u16(&bam[16..18]) as usize + 1;
+        bam.splice(offset..offset, block);
+        let mut output:Vec<u8> = Vec::new();
+        let report = Salvager::new("-").partial(true).deflate_resync(true).provenance_tags(true)
+            .run_reader(Cursor::new(bam), &mut output).unwrap();
+        assert_eq!(report.n_partial_blocks, 1);
+        let text = String::from_utf8(output).unwrap();
+        let provenance = |name:&str| text.lines().find(|l_| l_.starts_with(&format!("@{}\t", name))).map(|l_| l_.split_once("\tZO:").unwrap().1.to_string());
+        assert_eq!(provenance("read4"), Some(format!("i:{}\tZU:i:{}\tZR:i:1", offset, head.len())));
+        assert_eq!(provenance("read5"), Some(format!("i:{}\tZU:i:{}\tZR:i:0", offset, head.len() + records[4].len())));
+    }
+
+    #[test]
+    fn provenance_tsv() {
+        let records:Vec<Vec<u8>> = (0..3).map(|i_| make_record(&format!("read{}", i_), 0, &[10 << 4], &[30; 10])).collect();
+        let bam = bam_of(&records);
+        let offset = LittleEndian::read_u16(&bam[16..18]) as u64 + 1;
+        let filename = std::env::temp_dir().join(format!("bamsalvage_provenance_{}.tsv", std::process::id()));
+        Salvager::new("-").provenance_tsv(filename.to_str().unwrap()).run_reader(Cursor::new(bam), &mut Vec::<u8>::new()).unwrap();
+        let tsv = std::fs::read_to_string(&filename).unwrap();
+        std::fs::remove_file(&filename).unwrap();
+        let lines:Vec<&str> = tsv.lines().collect();
+        assert_eq!(lines[0], "name\tflag\tblock_offset\toffset_in_block\tvirtual_offset\tafter_resync\tpartial");
+        assert_eq!(lines[1], format!("read0\t0\t{}\t0\t{}\t0\t0", offset, offset << 16));
+        assert_eq!(lines[3], format!("read2\t0\t{}\t{}\t{}\t0\t0", offset, records[0].len() * 2, (offset << 16) + records[0].len() as u64 * 2));
+    }
+}
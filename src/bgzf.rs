@@ -0,0 +1,288 @@
+// BGZF writer, data are split into blocks of up to 65280 bytes and each block is compressed as a gzip member
+// with BC extra field carrying the block size. Blocks are compressed by worker threads if requested
+// and written in order by the calling thread.
+
+use std::io::{self, Write};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::thread;
+use byteorder::{ByteOrder, LittleEndian};
+use flate2::{Compress, Compression, FlushCompress, Status};
+use crc32fast::Hasher;
+
+// Maximum size of uncompressed data in a block, same as htslib
+pub const BGZF_BLOCK_DATA_SIZE:usize = 0xff00;
+const BGZF_MAX_BLOCK_SIZE:usize = 0x10000;
+const BGZF_HEADER:[u8;16] = [31, 139, 8, 4, 0, 0, 0, 0, 0, 255, 6, 0, 66, 67, 2, 0];
+
+/// Empty block terminating BGZF files
+pub const BGZF_EOF:[u8;28] = [31, 139, 8, 4, 0, 0, 0, 0, 0, 255, 6, 0, 66, 67, 2, 0, 27, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+// Raw deflate of data, None if the output does not fit into a block
+fn deflate(data:&[u8], level:Compression) -> Option<Vec<u8>> {
+    let mut compressor = Compress::new(level, false);
+    let mut cdata:Vec<u8> = Vec::with_capacity(BGZF_MAX_BLOCK_SIZE);
+    match compressor.compress_vec(data, &mut cdata, FlushCompress::Finish) {
+        Ok(Status::StreamEnd) => Some(cdata),
+        _ => None,
+    }
+}
+
+/// Compress data into a BGZF block. Data larger than BGZF_BLOCK_DATA_SIZE are not accepted.
+pub fn compress_block(data:&[u8], level:Compression) -> Vec<u8> {
+    assert!(data.len() <= BGZF_BLOCK_DATA_SIZE);
+    // incompressible data are stored without compression
+    let cdata = match deflate(data, level) {
+        Some(c_) if c_.len() + 26 <= BGZF_MAX_BLOCK_SIZE => c_,
+        _ => deflate(data, Compression::none()).unwrap(),
+    };
+    let mut hasher = Hasher::new();
+    hasher.update(data);
+    let mut block:Vec<u8> = Vec::with_capacity(cdata.len() + 26);
+    block.extend_from_slice(&BGZF_HEADER);
+    let mut buf:[u8;4] = [0;4];
+    LittleEndian::write_u16(&mut buf[0..2], (cdata.len() + 25) as u16);
+    block.extend_from_slice(&buf[0..2]);
+    block.extend_from_slice(&cdata);
+    LittleEndian::write_u32(&mut buf, hasher.finalize());
+    block.extend_from_slice(&buf);
+    LittleEndian::write_u32(&mut buf, data.len() as u32);
+    block.extend_from_slice(&buf);
+    block
+}
+
+// Number of blocks compressed ahead of writing per worker
+const BLOCKS_PER_WORKER:usize = 4;
+
+// Block data numbered in the order of writing
+type NumberedBlock = (u64, Vec<u8>);
+
+// Worker threads compressing blocks, blocks are numbered to be written in order
+struct CompressPool {
+    jobs:SyncSender<NumberedBlock>,
+    results:Receiver<NumberedBlock>,
+    pending:HashMap<u64, Vec<u8>>,
+    next_job:u64,
+    next_write:u64,
+    window:usize,
+}
+
+fn compress_blocks(jobs:Arc<Mutex<Receiver<NumberedBlock>>>, results:Sender<NumberedBlock>, level:Compression) {
+    loop {
+        let job = jobs.lock().unwrap().recv();
+        match job {
+            Ok((index_, data_)) => {
+                if results.send((index_, compress_block(&data_, level))).is_err() {
+                    return;
+                }
+            },
+            Err(_) => return, // writer dropped
+        }
+    }
+}
+
+impl CompressPool {
+    fn new(level:Compression, n_threads:usize) -> CompressPool {
+        let window = n_threads * BLOCKS_PER_WORKER;
+        let (job_tx, job_rx) = sync_channel::<NumberedBlock>(window);
+        let (result_tx, result_rx) = channel::<NumberedBlock>();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        for _ in 0..n_threads {
+            let job_rx = Arc::clone(&job_rx);
+            let result_tx = result_tx.clone();
+            thread::spawn(move || compress_blocks(job_rx, result_tx, level));
+        }
+        CompressPool{jobs:job_tx, results:result_rx, pending:HashMap::new(), next_job:0, next_write:0, window}
+    }
+
+    // Write compressed blocks in order, waiting until at most remaining blocks are in progress
+    fn write_ready<W:Write>(&mut self, inner:&mut W, remaining:usize) -> io::Result<()> {
+        loop {
+            while let Some(block_) = self.pending.remove(&self.next_write) {
+                inner.write_all(&block_)?;
+                self.next_write += 1;
+            }
+            if (self.next_job - self.next_write) as usize <= remaining {
+                return Ok(());
+            }
+            match self.results.recv() {
+                Ok((index_, block_)) => {
+                    self.pending.insert(index_, block_);
+                },
+                Err(_) => return Err(io::Error::other("compression threads terminated")),
+            }
+        }
+    }
+}
+
+/// Writer compressing data into BGZF blocks. The EOF block is written by finish() or when the writer is dropped.
+/// Errors are only reported by finish(), dropping the writer ignores them.
+pub struct BgzfWriter<W:Write> {
+    inner:W,
+    buffer:Vec<u8>,
+    level:Compression,
+    finished:bool,
+    failed:bool, // the inner writer failed, all later writes fail
+    pool:Option<CompressPool>,
+}
+
+impl<W:Write> BgzfWriter<W> {
+    pub fn new(inner:W) -> BgzfWriter<W> {
+        BgzfWriter::with_level(inner, Compression::default())
+    }
+
+    pub fn with_level(inner:W, level:Compression) -> BgzfWriter<W> {
+        BgzfWriter{inner, buffer:Vec::with_capacity(BGZF_BLOCK_DATA_SIZE), level, finished:false, failed:false, pool:None}
+    }
+
+    /// Blocks are compressed by n_threads worker threads, or in the calling thread if n_threads is less than 2
+    pub fn with_threads(inner:W, level:Compression, n_threads:usize) -> BgzfWriter<W> {
+        let mut writer = BgzfWriter::with_level(inner, level);
+        if n_threads >= 2 {
+            writer.pool = Some(CompressPool::new(level, n_threads));
+        }
+        writer
+    }
+
+    // Compress buffered data into a block. Data are discarded and the writer is poisoned if writing fails.
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.failed {
+            return Err(io::Error::other("BGZF writer failed previously"));
+        }
+        let result = self.compress_buffer();
+        if result.is_err() {
+            self.buffer.clear();
+            self.failed = true;
+        }
+        result
+    }
+
+    fn compress_buffer(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            match self.pool.as_mut() {
+                Some(pool_) => {
+                    let data = std::mem::replace(&mut self.buffer, Vec::with_capacity(BGZF_BLOCK_DATA_SIZE));
+                    pool_.jobs.send((pool_.next_job, data)).map_err(|_| io::Error::other("compression threads terminated"))?;
+                    pool_.next_job += 1;
+                    let window = pool_.window;
+                    pool_.write_ready(&mut self.inner, window)?;
+                },
+                None => {
+                    let block = compress_block(&self.buffer, self.level);
+                    self.buffer.clear();
+                    self.inner.write_all(&block)?;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    // Wait for blocks compressed by workers
+    fn write_all_blocks(&mut self) -> io::Result<()> {
+        self.write_block()?;
+        if let Some(pool_) = self.pool.as_mut() {
+            if let Err(e_) = pool_.write_ready(&mut self.inner, 0) {
+                self.failed = true;
+                return Err(e_);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write remaining data and the EOF block
+    pub fn finish(&mut self) -> io::Result<()> {
+        if !self.finished {
+            self.write_all_blocks()?;
+            self.finished = true; // the EOF block is not written again after a failure
+            self.inner.write_all(&BGZF_EOF)?;
+            self.inner.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl<W:Write> Write for BgzfWriter<W> {
+    fn write(&mut self, data:&[u8]) -> io::Result<usize> {
+        if self.failed {
+            return Err(io::Error::other("BGZF writer failed previously"));
+        }
+        let size = data.len().min(BGZF_BLOCK_DATA_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&data[0..size]);
+        if self.buffer.len() >= BGZF_BLOCK_DATA_SIZE {
+            self.write_block()?;
+        }
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_all_blocks()?;
+        self.inner.flush()
+    }
+}
+
+impl<W:Write> Drop for BgzfWriter<W> {
+    fn drop(&mut self) {
+        if !self.failed {
+            let _ = self.finish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use flate2::read::MultiGzDecoder;
+
+    // Writer accepting a limited number of bytes
+    struct LimitedWriter {
+        written:usize,
+        limit:usize,
+    }
+
+    impl Write for LimitedWriter {
+        fn write(&mut self, data:&[u8]) -> io::Result<usize> {
+            if self.written + data.len() > self.limit {
+                return Err(io::Error::other("no space"));
+            }
+            self.written += data.len();
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn text(length:usize) -> Vec<u8> {
+        (0..length).map(|i_| b"ACGTN\n"[(i_ * 7 + i_ / 13) % 6]).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = text(300000);
+        for n_threads in [1, 3] {
+            let mut compressed:Vec<u8> = Vec::new();
+            let mut writer = BgzfWriter::with_threads(&mut compressed, Compression::default(), n_threads);
+            writer.write_all(&data).unwrap();
+            writer.finish().unwrap();
+            drop(writer);
+            assert!(compressed.ends_with(&BGZF_EOF));
+            let mut inflated:Vec<u8> = Vec::new();
+            MultiGzDecoder::new(&compressed[..]).read_to_end(&mut inflated).unwrap();
+            assert_eq!(inflated, data);
+        }
+    }
+
+    #[test]
+    fn failed_writer() {
+        for n_threads in [1, 3] {
+            let mut writer = BgzfWriter::with_threads(LimitedWriter{written:0, limit:100}, Compression::default(), n_threads);
+            assert!(writer.write_all(&text(1000000)).is_err());
+            assert!(writer.write(b"ACGT").is_err());
+            assert!(writer.finish().is_err());
+            assert!(writer.inner.written <= 100);
+        }
+    }
+}
@@ -0,0 +1,214 @@
+// BAM header, SAM header text and the reference dictionary
+
+use std::str;
+use byteorder::{ByteOrder, LittleEndian};
+
+// Headers larger than this are regarded as corrupted
+const MAX_HEADER_SIZE:usize = 1 << 28;
+
+/// A reference sequence of the dictionary
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reference {
+    pub name:String,
+    pub length:u32,
+}
+
+/// A line of SAM header such as @SQ, fields are kept in the order of the line
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeaderLine {
+    pub tag:String,
+    pub fields:Vec<(String,String)>,
+}
+
+impl HeaderLine {
+    fn parse(line:&str) -> Option<HeaderLine> {
+        let mut items = line.split('\t');
+        let tag = items.next()?.strip_prefix('@')?;
+        if tag.len() != 2 {
+            return None;
+        }
+        let fields = items.filter_map(|item_| {
+            let (key_, value_) = item_.split_once(':')?;
+            Some((key_.to_string(), value_.to_string()))
+        }).collect();
+        Some(HeaderLine{tag:tag.to_string(), fields})
+    }
+
+    /// Value of a field such as SN
+    pub fn get(&self, key:&str) -> Option<&str> {
+        self.fields.iter().find(|(k_, _)| k_ == key).map(|(_, v_)| v_.as_str())
+    }
+}
+
+/// Header of BAM file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BamHeader {
+    pub text:String,
+    pub lines:Vec<HeaderLine>, // lines other than @CO
+    pub comments:Vec<String>,  // @CO lines
+    pub references:Vec<Reference>,
+}
+
+pub(crate) enum HeaderParse {
+    Complete(BamHeader, usize), // header and its size in bytes
+    Incomplete,
+    Invalid,
+}
+
+impl BamHeader {
+    // Decode header lines of text
+    fn parse_text(text:&str) -> (Vec<HeaderLine>, Vec<String>) {
+        let mut lines:Vec<HeaderLine> = Vec::new();
+        let mut comments:Vec<String> = Vec::new();
+        for line in text.trim_end_matches('\0').lines() {
+            if let Some(comment_) = line.strip_prefix("@CO\t") {
+                comments.push(comment_.to_string());
+            } else if let Some(line_) = HeaderLine::parse(line) {
+                lines.push(line_);
+            }
+        }
+        (lines, comments)
+    }
+
+    /// Decode BAM header at the head of decompressed data
+    pub(crate) fn parse(buffer:&[u8]) -> HeaderParse {
+        if buffer.len() < 4 {
+            return HeaderParse::Incomplete;
+        }
+        if buffer[0..4] != [66, 65, 77, 1] {
+            return HeaderParse::Invalid;
+        }
+        if buffer.len() < 8 {
+            return HeaderParse::Incomplete;
+        }
+        let l_text = LittleEndian::read_u32(&buffer[4..8]) as usize;
+        if l_text > MAX_HEADER_SIZE {
+            return HeaderParse::Invalid;
+        }
+        if buffer.len() < 12 + l_text {
+            return HeaderParse::Incomplete;
+        }
+        let text = String::from_utf8_lossy(&buffer[8..8 + l_text]).to_string();
+        let n_ref = LittleEndian::read_u32(&buffer[8 + l_text..12 + l_text]) as usize;
+        if n_ref > MAX_HEADER_SIZE / 9 {
+            return HeaderParse::Invalid;
+        }
+        let mut references:Vec<Reference> = Vec::with_capacity(n_ref);
+        let mut pos = 12 + l_text;
+        for _ in 0..n_ref {
+            if buffer.len() < pos + 4 {
+                return HeaderParse::Incomplete;
+            }
+            let l_name = LittleEndian::read_u32(&buffer[pos..pos + 4]) as usize;
+            if l_name == 0 || pos + l_name > MAX_HEADER_SIZE {
+                return HeaderParse::Invalid;
+            }
+            if buffer.len() < pos + 8 + l_name {
+                return HeaderParse::Incomplete;
+            }
+            let name = String::from_utf8_lossy(&buffer[pos + 4..pos + 3 + l_name]).to_string();
+            let length = LittleEndian::read_u32(&buffer[pos + 4 + l_name..pos + 8 + l_name]);
+            references.push(Reference{name, length});
+            pos += 8 + l_name;
+        }
+        let (lines, comments) = BamHeader::parse_text(&text);
+        HeaderParse::Complete(BamHeader{text, lines, comments, references}, pos)
+    }
+
+    /// Header given by SAM header text such as output of samtools view -H, references are taken from @SQ lines
+    pub fn from_text(text:&str) -> BamHeader {
+        let mut text = text.to_string();
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        let (lines, comments) = BamHeader::parse_text(&text);
+        let references = lines.iter().filter(|l_| l_.tag == "SQ").filter_map(|l_| {
+            let name = l_.get("SN")?.to_string();
+            let length = l_.get("LN")?.parse::<u32>().ok()?;
+            Some(Reference{name, length})
+        }).collect();
+        BamHeader{text, lines, comments, references}
+    }
+
+    /// Encode into BAM header
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes:Vec<u8> = vec![66, 65, 77, 1];
+        let mut buf:[u8;4] = [0;4];
+        LittleEndian::write_u32(&mut buf, self.text.len() as u32);
+        bytes.extend_from_slice(&buf);
+        bytes.extend_from_slice(self.text.as_bytes());
+        LittleEndian::write_u32(&mut buf, self.references.len() as u32);
+        bytes.extend_from_slice(&buf);
+        for reference in &self.references {
+            LittleEndian::write_u32(&mut buf, reference.name.len() as u32 + 1);
+            bytes.extend_from_slice(&buf);
+            bytes.extend_from_slice(reference.name.as_bytes());
+            bytes.push(0);
+            LittleEndian::write_u32(&mut buf, reference.length);
+            bytes.extend_from_slice(&buf);
+        }
+        bytes
+    }
+
+    /// SAM header text. @SQ lines are reconstructed from the reference dictionary if the text lacks them.
+    pub fn to_sam(&self) -> String {
+        let mut text = self.text.trim_end_matches('\0').to_string();
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        if self.sequences().is_empty() && !self.references.is_empty() {
+            let sq:String = self.references.iter().map(|r_| format!("@SQ\tSN:{}\tLN:{}\n", r_.name, r_.length)).collect();
+            // @SQ lines follow @HD
+            let pos = if text.starts_with("@HD") { text.find('\n').map_or(text.len(), |p_| p_ + 1) } else { 0 };
+            text.insert_str(pos, &sq);
+        }
+        text
+    }
+
+    /// Append a @PG line chained to the last @PG line by PP. The ID is numbered if already used.
+    pub fn add_program(&mut self, name:&str, version:&str) {
+        let programs:Vec<String> = self.programs().iter().filter_map(|l_| l_.get("ID")).map(|id_| id_.to_string()).collect();
+        let mut id = name.to_string();
+        let mut n = 0;
+        while programs.contains(&id) {
+            n += 1;
+            id = format!("{}.{}", name, n);
+        }
+        let mut line = format!("@PG\tID:{}\tPN:{}\tVN:{}", id, name, version);
+        if let Some(last_) = programs.last() {
+            line += &format!("\tPP:{}", last_);
+        }
+        self.text = self.text.trim_end_matches('\0').to_string();
+        if !self.text.is_empty() && !self.text.ends_with('\n') {
+            self.text.push('\n');
+        }
+        self.text += &line;
+        self.text.push('\n');
+        self.lines.extend(HeaderLine::parse(&line));
+    }
+
+    /// Lines of the given tag
+    pub fn lines_of(&self, tag:&str) -> Vec<&HeaderLine> {
+        self.lines.iter().filter(|l_| l_.tag == tag).collect()
+    }
+
+    /// @HD line
+    pub fn hd(&self) -> Option<&HeaderLine> {
+        self.lines.iter().find(|l_| l_.tag == "HD")
+    }
+
+    /// @SQ lines
+    pub fn sequences(&self) -> Vec<&HeaderLine> {
+        self.lines_of("SQ")
+    }
+
+    /// @RG lines
+    pub fn read_groups(&self) -> Vec<&HeaderLine> {
+        self.lines_of("RG")
+    }
+
+    /// @PG lines
+    pub fn programs(&self) -> Vec<&HeaderLine> {
+        self.lines_of("PG")
+    }
+}
@@ -0,0 +1,19 @@
+//! Extraction of reads from possibly corrupted BAM files
+
+mod bamloader;
+mod pipeline;
+mod salvager;
+mod records;
+mod header;
+mod pairing;
+mod tags;
+mod modifications;
+mod quarantine;
+pub mod bgzf;
+
+pub use bamloader::{BamErrorKind, BamHandleError, BlockInfo, CrcStatus, write_block_map_tsv, write_block_map_json};
+pub use salvager::{MissingQual, OutputFormat, Salvager, SalvageReport, REPORT_SCHEMA_VERSION};
+pub use header::{BamHeader, HeaderLine, Reference};
+pub use pairing::PairedOutputs;
+pub use tags::{AuxField, AuxValue};
+pub use records::{RecordIterator, SalvagedRecord, SalvageEvent};
@@ -1,30 +1,18 @@
-#![allow(unused)]
-#[allow(unused_variables)]
+use std::io::{self, BufWriter, Write};
 
-mod bamloader;
+use clap::{ArgGroup, CommandFactory, Parser};
+use clap::error::ErrorKind;
 
-use std;
-use std::fs;
-use std::hash::Hash;
-use std::string;
-use std::io::Error;
-use std::collections::HashMap;
-use clap::{Command, Arg, ArgAction};
-
-use std::io::BufReader;
-use std::io::prelude::*;
-use std::io::{self, BufRead, BufWriter, Write};
-
-use std::path::PathBuf;
-
-use clap::{Parser, Subcommand};
+use bamsalvage::bgzf::BgzfWriter;
+use bamsalvage::{BamHeader, MissingQual, OutputFormat, PairedOutputs, Salvager};
 
 #[derive(Parser)]
 #[command(author="Takaho A. Endo")]
 #[command(about="Extraction of reads from BAM", long_about="Software extracting seqquence reads as much as possible from possibly corrupted BAM files.")]
 #[command(author, version, about, long_about = None)]
+#[command(group(ArgGroup::new("named_output").args(["output", "read1"]).multiple(true)))]
 struct Cli {
-    /// Input BAM file
+    /// Input BAM file, - to read from stdin
     #[arg(short, long, value_name="FILE")]
     input: String,
 
@@ -43,6 +31,210 @@ struct Cli {
     /// verbosity
     #[arg(short, long)]
     verbose:bool,
+
+    /// Salvage bytes inflated before the damage of corrupted blocks
+    #[arg(short, long)]
+    partial:bool,
+
+    /// Search deflate blocks decodable after the damage of corrupted blocks
+    #[arg(short, long)]
+    deflate_resync:bool,
+
+    /// Repair corrupted blocks by flipping up to 1 or 2 bits, 0 to disable
+    #[arg(short, long, value_name="bits", default_value="0")]
+    repair:u32,
+
+    /// Corruption map of BGZF blocks, JSON if the name ends with .json otherwise TSV
+    #[arg(short, long, value_name="FILE")]
+    blockmap: Option<String>,
+
+    /// Number of threads decompressing blocks and compressing BGZF output, 1 to process in a single thread
+    #[arg(short, long, value_name="integer", default_value="1")]
+    threads:usize,
+
+    /// Write only records having all of the flag bits, decimal or hexadecimal with 0x
+    #[arg(short='f', long, value_name="FLAG", default_value="0", value_parser=parse_flags)]
+    require_flags:u16,
+
+    /// Skip records having any of the flag bits, secondary and supplementary alignments (0x900) by default in fastq and fasta
+    #[arg(short='F', long, value_name="FLAG", value_parser=parse_flags)]
+    exclude_flags:Option<u16>,
+
+    /// Output of read 1 (flags 0x1 and 0x40) paired with read 2
+    #[arg(short='1', long, value_name="FILE", requires="read2")]
+    read1:Option<String>,
+
+    /// Output of read 2 (flags 0x1 and 0x80) paired with read 1
+    #[arg(short='2', long, value_name="FILE", requires="read1")]
+    read2:Option<String>,
+
+    /// Output of mates whose partner is lost, the main output by default
+    #[arg(short='s', long, value_name="FILE", requires="read1")]
+    singleton:Option<String>,
+
+    /// Output of reads having neither or both of 0x40 and 0x80, the main output by default
+    #[arg(short='0', long, value_name="FILE", requires="read1")]
+    other:Option<String>,
+
+    /// Number of mates waiting for their partners in split of paired-end reads
+    #[arg(long, value_name="integer", default_value="100000")]
+    mate_buffer:usize,
+
+    /// Tags appended to the header lines in SAM format, e.g. CB,UB,MM,ML
+    #[arg(short='T', long, value_name="TAGS", value_delimiter=',')]
+    tags:Vec<String>,
+
+    /// Compression of outputs, none, gzip or bgzf, by default bgzf for names ending with .gz or .bgz
+    #[arg(long, value_name="none|gzip|bgzf", value_parser=parse_compress)]
+    compress:Option<OutputCompression>,
+
+    /// Origin of reads, tags in the header lines (ZO:i block offset, ZU:i offset in the block, ZR:i resync before the read)
+    /// or tsv written into OUTPUT.provenance.tsv
+    #[arg(long, value_name="tags|tsv", value_parser=parse_provenance, requires_if("tsv", "named_output"))]
+    provenance:Option<Provenance>,
+
+    /// Summary of the run in JSON
+    #[arg(long, value_name="FILE")]
+    report:Option<String>,
+
+    /// Directory where corrupted compressed ranges and partially inflated data are dumped with index.tsv
+    #[arg(long, value_name="DIR")]
+    quarantine:Option<String>,
+
+    /// Output format, fastq (fasta with --noqual), fasta, bam or sam
+    #[arg(long, value_name="fastq|fasta|bam|sam", default_value="fastq", value_parser=parse_format)]
+    format:OutputFormat,
+
+    /// Number of headers chained by BSIZE to confirm a block found by scan, 0 to accept the first candidate
+    #[arg(long, value_name="integer", default_value="1")]
+    scan_chain:usize,
+
+    /// Phred score written for reads without QUAL in FASTQ, or fasta to write them as FASTA records
+    #[arg(long, value_name="integer|fasta", default_value="1", value_parser=parse_missing_qual)]
+    missing_qual:MissingQual,
+
+    /// Write reverse-strand reads as stored in BAM instead of the orientation of the sequencer
+    #[arg(long)]
+    stored_orientation:bool,
+
+    /// Go on without the header if the first block is lost
+    #[arg(long)]
+    headerless:bool,
+
+    /// SAM header text used if the header is lost, such as output of samtools view -H of a sibling BAM
+    #[arg(long, value_name="FILE")]
+    header:Option<String>,
+}
+
+// Flags given in decimal or hexadecimal as samtools accepts
+fn parse_flags(value:&str) -> Result<u16, String> {
+    let parsed = match value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+        Some(hex_) => u16::from_str_radix(hex_, 16),
+        None => value.parse::<u16>(),
+    };
+    parsed.map_err(|e_| format!("invalid flag {}: {}", value, e_))
+}
+
+// Phred score of the placeholder or fasta
+fn parse_missing_qual(value:&str) -> Result<MissingQual, String> {
+    if value.eq_ignore_ascii_case("fasta") {
+        return Ok(MissingQual::Fasta);
+    }
+    value.parse::<u8>().map(MissingQual::Placeholder).map_err(|_| format!("missing-qual should be fasta or a Phred score, not {}", value))
+}
+
+fn parse_format(value:&str) -> Result<OutputFormat, String> {
+    match value.to_lowercase().as_str() {
+        "fastq" => Ok(OutputFormat::Fastq),
+        "fasta" => Ok(OutputFormat::Fasta),
+        "bam" => Ok(OutputFormat::Bam),
+        "sam" => Ok(OutputFormat::Sam),
+        _ => Err(format!("unknown output format {}, fastq, fasta, bam or sam", value)),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum OutputCompression {
+    None,
+    Gzip,
+    Bgzf,
+}
+
+fn parse_compress(value:&str) -> Result<OutputCompression, String> {
+    match value.to_lowercase().as_str() {
+        "none" => Ok(OutputCompression::None),
+        "gzip" => Ok(OutputCompression::Gzip),
+        "bgzf" => Ok(OutputCompression::Bgzf),
+        _ => Err(format!("unknown compression {}, none, gzip or bgzf", value)),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Provenance {
+    Tags,
+    Tsv,
+}
+
+fn parse_provenance(value:&str) -> Result<Provenance, String> {
+    match value {
+        "tags" => Ok(Provenance::Tags),
+        "tsv" => Ok(Provenance::Tsv),
+        _ => Err(format!("unknown provenance {}, tags or tsv", value)),
+    }
+}
+
+// Output file or stdout. Compressed outputs are finished explicitly, since errors in writing trailers are lost in drop.
+enum Output {
+    Plain(Box<dyn Write>),
+    Gzip(flate2::write::GzEncoder<Box<dyn Write>>),
+    Bgzf(BgzfWriter<Box<dyn Write>>),
+}
+
+impl Write for Output {
+    fn write(&mut self, data:&[u8]) -> io::Result<usize> {
+        match self {
+            Output::Plain(w_) => w_.write(data),
+            Output::Gzip(w_) => w_.write(data),
+            Output::Bgzf(w_) => w_.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Plain(w_) => w_.flush(),
+            Output::Gzip(w_) => w_.flush(),
+            Output::Bgzf(w_) => w_.flush(),
+        }
+    }
+}
+
+impl Output {
+    // Write the gzip trailer or the BGZF EOF block and flush the file
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Output::Plain(mut w_) => w_.flush(),
+            Output::Gzip(w_) => w_.finish()?.flush(),
+            Output::Bgzf(mut w_) => w_.finish(),
+        }
+    }
+}
+
+// Output file or stdout, compressed as given or by the extension
+fn open_output(filename:Option<&String>, compress:Option<OutputCompression>, n_threads:usize) -> Output {
+    let compress = match (compress, filename) {
+        (Some(c_), _) => c_,
+        (None, Some(f_)) if f_.ends_with(".gz") || f_.ends_with(".bgz") => OutputCompression::Bgzf,
+        _ => OutputCompression::None,
+    };
+    let writer:Box<dyn Write> = match filename {
+        Some(f_) => Box::new(BufWriter::new(std::fs::File::create(f_).expect("failed to create a file"))),
+        None => Box::new(io::stdout()),
+    };
+    match compress {
+        OutputCompression::None => Output::Plain(writer),
+        OutputCompression::Gzip => Output::Gzip(flate2::write::GzEncoder::new(writer, flate2::Compression::default())),
+        OutputCompression::Bgzf => Output::Bgzf(BgzfWriter::with_threads(writer, flate2::Compression::default(), n_threads)),
+    }
 }
 
 fn main() {
@@ -53,33 +245,86 @@ fn main() {
     let verbose = cli.verbose;
     let limit = cli.limit;
     let noqual = cli.noqual;
-    let mut output:Box<dyn Write> = match cli.output {
-        Some(v_) => {
-            Box::new(BufWriter::new(std::fs::File::create(v_).expect("failed to create a file")))
-        },
-        None=>Box::new(io::stdout()),
-    };
+    let format = if cli.format == OutputFormat::Fastq && noqual { OutputFormat::Fasta } else { cli.format };
+    if cli.read1.is_some() && (format == OutputFormat::Bam || format == OutputFormat::Sam) {
+        Cli::command().error(ErrorKind::ArgumentConflict, "paired-end outputs -1/-2 are available in fastq or fasta").exit();
+    }
+    // BAM output is compressed by the salvager
+    let compress = if format == OutputFormat::Bam { Some(OutputCompression::None) } else { cli.compress };
+    let mut output = open_output(cli.output.as_ref(), compress, cli.threads);
 
-    let info = HashMap::from(
-        [
-            ("limit", limit as i32), 
-            ("verbose", if verbose {1} else {0}),
-            ("noqual", if noqual {1} else {0})
-        ]
-    );
-
-    let mut results:HashMap<String,String> = HashMap::<String,String>::new();
-    match bamloader::retrieve_fastq(&input, &mut output, info) {
-        Ok(res_)=>{
-            for (key,val) in res_ {
-                results.insert(key, val);
+    let mut salvager = Salvager::new(&input)
+        .format(format)
+        .limit(limit as u64)
+        .verbose(verbose)
+        .partial(cli.partial)
+        .deflate_resync(cli.deflate_resync)
+        .repair(cli.repair as usize)
+        .threads(cli.threads)
+        .block_map(cli.blockmap.is_some())
+        .scan_chain(cli.scan_chain)
+        .missing_qual(cli.missing_qual)
+        .original_orientation(!cli.stored_orientation)
+        .require_flags(cli.require_flags)
+        .mate_buffer(cli.mate_buffer)
+        .tags(&cli.tags)
+        .headerless(cli.headerless);
+    if let Some(flags_) = cli.exclude_flags {
+        salvager = salvager.exclude_flags(flags_);
+    }
+    match cli.provenance {
+        Some(Provenance::Tags) => salvager = salvager.provenance_tags(true),
+        Some(Provenance::Tsv) => { // -o or -1 is required by the parser
+            if let Some(base_) = cli.output.as_ref().or(cli.read1.as_ref()) {
+                salvager = salvager.provenance_tsv(&format!("{}.provenance.tsv", base_));
             }
         },
-        Err(e_)=>panic!("{:?}", e_),
+        None => (),
+    }
+    if let Some(dir_quarantine) = cli.quarantine.as_ref() {
+        salvager = salvager.quarantine(dir_quarantine);
+    }
+    if let Some(filename_header) = cli.header {
+        let text = std::fs::read_to_string(&filename_header).expect("failed to read header file");
+        salvager = salvager.header(BamHeader::from_text(&text));
+    }
+    let report = match (cli.read1, cli.read2) {
+        (Some(filename1_), Some(filename2_)) => {
+            let create = |f_:&String| -> Output { open_output(Some(f_), compress, cli.threads) };
+            let mut read1 = create(&filename1_);
+            let mut read2 = create(&filename2_);
+            let mut singleton = cli.singleton.as_ref().map(create);
+            let mut other = cli.other.as_ref().map(create);
+            let paired = PairedOutputs{read1:&mut read1, read2:&mut read2,
+                singleton:singleton.as_mut().map(|w_| w_ as &mut dyn Write), other:other.as_mut().map(|w_| w_ as &mut dyn Write)};
+            let report = salvager.run_paired(&mut output, paired);
+            if report.is_ok() {
+                for writer in [Some(read1), Some(read2), singleton, other].into_iter().flatten() {
+                    writer.finish().expect("failed to write output");
+                }
+            }
+            report
+        },
+        _ => salvager.run(&mut output),
+    };
+    let report = match report {
+        Ok(r_) => r_,
+        Err(e_) => panic!("{}", e_),
+    };
+    output.finish().expect("failed to write output");
+    if let Some(filename_map) = cli.blockmap {
+        let mut writer = BufWriter::new(std::fs::File::create(&filename_map).expect("failed to create a file"));
+        let written = if filename_map.ends_with(".json") {
+            bamsalvage::write_block_map_json(&report.block_map, &mut writer)
+        } else {
+            bamsalvage::write_block_map_tsv(&report.block_map, &mut writer)
+        };
+        written.and_then(|_| writer.flush()).expect("failed to write block map");
     }
-    for (key, val) in &results {
-        eprintln!("{}={}", key, val);
+    if let Some(filename_report) = cli.report {
+        let mut writer = BufWriter::new(std::fs::File::create(&filename_report).expect("failed to create a file"));
+        report.write_json(&mut writer).and_then(|_| writer.flush()).expect("failed to write report");
     }
-    println!("{:?}", results);
+    eprintln!("{}", report);
 
 }
\ No newline at end of file
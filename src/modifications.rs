@@ -0,0 +1,181 @@
+// Base modification tags MM, ML and MN.
+// Positions in MM refer to the read in the orientation of the sequencer, i.e. the reverse complement of SEQ if flag 0x10 is set.
+
+use std::str;
+
+use crate::bamloader::reverse_complement;
+use crate::records::SalvagedRecord;
+use crate::tags::{AuxField, AuxValue};
+
+// A group of MM such as C+m?,5,12,0
+struct ModGroup {
+    base:u8,
+    strand:u8,
+    codes:String, // modification codes with the optional mode . or ?
+    skips:Vec<usize>,
+}
+
+impl ModGroup {
+    // Number of ML values for each position, a ChEBI number is a single modification
+    fn n_codes(&self) -> usize {
+        let codes = self.codes.trim_end_matches(['.', '?']);
+        if codes.bytes().all(|c_| c_.is_ascii_digit()) { 1 } else { codes.len() }
+    }
+
+    fn is_candidate(&self, base:u8) -> bool {
+        self.base == b'N' || base == self.base || (self.base == b'U' && base == b'T')
+    }
+
+    // Positions of the modified bases in seq, None if skips run over the bases
+    fn positions(&self, seq:&[u8]) -> Option<Vec<usize>> {
+        let mut candidates = seq.iter().enumerate().filter(|(_, b_)| self.is_candidate(**b_)).map(|(i_, _)| i_);
+        self.skips.iter().map(|skip_| candidates.nth(*skip_)).collect()
+    }
+}
+
+fn parse_mm(text:&str) -> Option<Vec<ModGroup>> {
+    text.split(';').filter(|g_| !g_.is_empty()).map(|group_| {
+        let mut items = group_.split(',');
+        let head = items.next()?.as_bytes();
+        if head.len() < 3 || !b"ACGTUN".contains(&head[0]) || (head[1] != b'+' && head[1] != b'-') {
+            return None;
+        }
+        let codes = str::from_utf8(&head[2..]).ok()?.to_string();
+        let skips = items.map(|s_| s_.parse::<usize>().ok()).collect::<Option<Vec<usize>>>()?;
+        Some(ModGroup{base:head[0], strand:head[1], codes, skips})
+    }).collect()
+}
+
+fn find<'a>(fields:&'a [AuxField], tag:&str) -> Option<&'a AuxValue> {
+    fields.iter().find(|f_| f_.tag == tag).map(|f_| &f_.value)
+}
+
+/// Consistency of modification tags with the sequence
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ModCheck {
+    Absent,
+    Valid,
+    Invalid,
+}
+
+/// Test MM, ML and MN of a record. MN must be the length of SEQ, skips of MM must stay in the bases
+/// and ML must have a value for each modification.
+pub(crate) fn check_modifications(record:&SalvagedRecord, fields:&[AuxField]) -> ModCheck {
+    let mm = match find(fields, "MM") {
+        Some(AuxValue::String(s_)) => s_,
+        Some(_) => return ModCheck::Invalid,
+        None => return ModCheck::Absent,
+    };
+    match find(fields, "MN") {
+        Some(AuxValue::Int(n_)) if *n_ != record.l_seq() as i64 => return ModCheck::Invalid, // hard-clipped after modification calling
+        Some(AuxValue::Int(_)) | None => (),
+        Some(_) => return ModCheck::Invalid,
+    }
+    let groups = match parse_mm(mm) {
+        Some(g_) => g_,
+        None => return ModCheck::Invalid,
+    };
+    let seq = if record.is_reverse() { reverse_complement(&record.seq()) } else { record.seq() };
+    if groups.iter().any(|g_| g_.positions(seq.as_bytes()).is_none()) {
+        return ModCheck::Invalid;
+    }
+    let n_values:usize = groups.iter().map(|g_| g_.skips.len() * g_.n_codes()).sum();
+    match find(fields, "ML") {
+        Some(AuxValue::IntArray(b'C', values_)) if values_.len() == n_values => ModCheck::Valid,
+        None => ModCheck::Valid,
+        Some(_) => ModCheck::Invalid,
+    }
+}
+
+/// MM and ML of a reverse-strand record for SEQ as stored in BAM, None if MM is absent or inconsistent
+pub(crate) fn stored_modifications(record:&SalvagedRecord, fields:&[AuxField]) -> Option<(String, Vec<i64>)> {
+    let mm = match find(fields, "MM") {
+        Some(AuxValue::String(s_)) => s_,
+        _ => return None,
+    };
+    let ml:&[i64] = match find(fields, "ML") {
+        Some(AuxValue::IntArray(_, values_)) => values_,
+        _ => &[],
+    };
+    reverse_modifications(mm, ml, &reverse_complement(&record.seq()))
+}
+
+// MM and ML for the reverse complement of seq, where seq is in the orientation of the sequencer.
+// Bases and strands are complemented, and skips and ML values are counted from the other end.
+fn reverse_modifications(mm:&str, ml:&[i64], seq:&str) -> Option<(String, Vec<i64>)> {
+    let groups = parse_mm(mm)?;
+    let reversed = reverse_complement(seq);
+    let mut text = String::new();
+    let mut values:Vec<i64> = Vec::with_capacity(ml.len());
+    let mut offset = 0;
+    for group in groups {
+        let length = seq.len();
+        let mut positions:Vec<usize> = group.positions(seq.as_bytes())?.iter().map(|p_| length - 1 - p_).collect();
+        positions.reverse();
+        let base = match group.base {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'T' | b'U' => b'A',
+            _ => b'N',
+        };
+        let complement = ModGroup{base, strand:if group.strand == b'+' { b'-' } else { b'+' }, codes:group.codes.clone(), skips:Vec::new()};
+        // skips are the numbers of unmodified candidate bases between modified ones
+        let mut skips:Vec<String> = Vec::with_capacity(positions.len());
+        let mut last = 0;
+        for pos in &positions {
+            let skip = reversed.as_bytes()[last..*pos].iter().filter(|b_| complement.is_candidate(**b_)).count();
+            skips.push(skip.to_string());
+            last = pos + 1;
+        }
+        text += &format!("{}{}{}", base as char, complement.strand as char, complement.codes);
+        for skip in &skips {
+            text += &format!(",{}", skip);
+        }
+        text += ";";
+        let n_codes = group.n_codes();
+        let size = group.skips.len() * n_codes;
+        if !ml.is_empty() {
+            let group_values = ml.get(offset..offset + size)?;
+            for chunk in group_values.chunks(n_codes).rev() {
+                values.extend_from_slice(chunk);
+            }
+        }
+        offset += size;
+    }
+    Some((text, values))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reverse_groups() {
+        // C of ACGTCGCA at 1 and 6 are modified, G of the reverse complement TGCGACGT at 1 and 6
+        let (mm, ml) = reverse_modifications("C+m,0,1;", &[200, 100], "ACGTCGCA").unwrap();
+        assert_eq!(mm, "G-m,0,1;");
+        assert_eq!(ml, vec![100, 200]);
+        // C at 4 and A at 0 are G at 3 and T at 7, two codes per position keep their order
+        let (mm, ml) = reverse_modifications("C+mh?,1;A+a.,0;", &[1, 2, 3], "ACGTCGCA").unwrap();
+        assert_eq!(mm, "G-mh?,1;T-a.,1;");
+        assert_eq!(ml, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn reverse_twice() {
+        let seq = "ACGTCGCATTCGGACCAGTC";
+        let (mm, ml) = reverse_modifications("C+m?,0,2,1;N+n,3,4;", &[10, 20, 30, 40, 50], seq).unwrap();
+        let (mm_, ml_) = reverse_modifications(&mm, &ml, &reverse_complement(seq)).unwrap();
+        assert_eq!(mm_, "C+m?,0,2,1;N+n,3,4;");
+        assert_eq!(ml_, vec![10, 20, 30, 40, 50]);
+    }
+
+    #[test]
+    fn inconsistent_groups() {
+        // skips running over the bases, ML shorter than MM
+        assert_eq!(reverse_modifications("C+m,3;", &[], "ACGTCGCA"), None);
+        assert_eq!(reverse_modifications("C+m,0,1;", &[200], "ACGTCGCA"), None);
+        assert_eq!(reverse_modifications("X+m,0;", &[], "ACGTCGCA"), None);
+    }
+}
@@ -0,0 +1,187 @@
+// Split of paired-end reads into files of read 1, read 2, singletons and others.
+// Mates are matched by read names in a buffer of bounded size, since collated BAM files keep mates close to each other.
+
+use std::io::{self, Write};
+use std::collections::{HashMap, VecDeque};
+
+/// Outputs of paired-end reads split by flags 0x1, 0x40 and 0x80 as samtools fastq -1/-2/-s/-0.
+/// Singletons and others are written into the main output if not given.
+pub struct PairedOutputs<'a> {
+    pub read1:&'a mut dyn Write,
+    pub read2:&'a mut dyn Write,
+    pub singleton:Option<&'a mut dyn Write>, // mates whose partner is lost
+    pub other:Option<&'a mut dyn Write>,     // unpaired reads and reads having neither or both of 0x40 and 0x80
+}
+
+// Result of adding a mate into the buffer
+enum Pairing {
+    Pair(Vec<u8>, Vec<u8>), // read 1 and read 2
+    Unknown(Vec<u8>),       // a mate evicted before its partner came
+}
+
+// Mates waiting for their partners
+struct MateBuffer {
+    mates:HashMap<String, (u64, bool, Vec<u8>)>, // name => serial, read 1 or not, text
+    order:VecDeque<(u64, String)>,               // order of arrival, entries of paired mates are removed lazily
+    capacity:usize,
+    serial:u64,
+}
+
+impl MateBuffer {
+    fn new(capacity:usize) -> MateBuffer {
+        MateBuffer{mates:HashMap::new(), order:VecDeque::new(), capacity:capacity.max(1), serial:0}
+    }
+
+    fn add(&mut self, name:&str, read1:bool, text:Vec<u8>) -> Option<Pairing> {
+        if let Some((_, read1_, text_)) = self.mates.remove(name) {
+            if read1_ != read1 { // partner
+                return Some(if read1 { Pairing::Pair(text, text_) } else { Pairing::Pair(text_, text) });
+            }
+            // the same mate appeared again, the former one is not paired
+            self.insert(name, read1, text);
+            return Some(Pairing::Unknown(text_));
+        }
+        self.insert(name, read1, text);
+        if self.mates.len() > self.capacity {
+            return self.pop().map(Pairing::Unknown);
+        }
+        None
+    }
+
+    fn insert(&mut self, name:&str, read1:bool, text:Vec<u8>) {
+        self.serial += 1;
+        self.mates.insert(name.to_string(), (self.serial, read1, text));
+        self.order.push_back((self.serial, name.to_string()));
+        if self.order.len() > self.capacity * 2 { // drop entries of paired mates
+            let mates = &self.mates;
+            self.order.retain(|(s_, n_)| mates.get(n_).is_some_and(|m_| m_.0 == *s_));
+        }
+    }
+
+    // The oldest mate waiting
+    fn pop(&mut self) -> Option<Vec<u8>> {
+        while let Some((serial, name)) = self.order.pop_front() {
+            if self.mates.get(&name).is_some_and(|m_| m_.0 == serial) {
+                return self.mates.remove(&name).map(|m_| m_.2);
+            }
+        }
+        None
+    }
+}
+
+pub(crate) struct PairedWriter<'a> {
+    outputs:PairedOutputs<'a>,
+    buffer:MateBuffer,
+    pub(crate) n_pairs:u64,
+    pub(crate) n_orphans:u64,
+    pub(crate) n_unknown:u64,
+}
+
+impl<'a> PairedWriter<'a> {
+    pub(crate) fn new(outputs:PairedOutputs<'a>, capacity:usize) -> PairedWriter<'a> {
+        PairedWriter{outputs, buffer:MateBuffer::new(capacity), n_pairs:0, n_orphans:0, n_unknown:0}
+    }
+
+    pub(crate) fn write(&mut self, flag:u16, name:&str, text:&[u8], fallback:&mut dyn Write) -> io::Result<()> {
+        let read1 = flag & 0x40 != 0;
+        let read2 = flag & 0x80 != 0;
+        if flag & 0x1 == 0 || read1 == read2 {
+            return match self.outputs.other.as_mut() {
+                Some(other_) => other_.write_all(text),
+                None => fallback.write_all(text),
+            };
+        }
+        match self.buffer.add(name, read1, text.to_vec()) {
+            Some(Pairing::Pair(text1_, text2_)) => {
+                self.outputs.read1.write_all(&text1_)?;
+                self.outputs.read2.write_all(&text2_)?;
+                self.n_pairs += 1;
+            },
+            Some(Pairing::Unknown(text_)) => {
+                self.write_singleton(&text_, fallback)?;
+                self.n_unknown += 1;
+            },
+            None => (),
+        }
+        Ok(())
+    }
+
+    fn write_singleton(&mut self, text:&[u8], fallback:&mut dyn Write) -> io::Result<()> {
+        match self.outputs.singleton.as_mut() {
+            Some(singleton_) => singleton_.write_all(text),
+            None => fallback.write_all(text),
+        }
+    }
+
+    // Mates left in the buffer are orphans if the input was read to the end, otherwise their partners are unknown
+    pub(crate) fn finish(&mut self, complete:bool, fallback:&mut dyn Write) -> io::Result<()> {
+        while let Some(text) = self.buffer.pop() {
+            self.write_singleton(&text, fallback)?;
+            if complete {
+                self.n_orphans += 1;
+            } else {
+                self.n_unknown += 1;
+            }
+        }
+        self.outputs.read1.flush()?;
+        self.outputs.read2.flush()?;
+        if let Some(singleton_) = self.outputs.singleton.as_mut() {
+            singleton_.flush()?;
+        }
+        if let Some(other_) = self.outputs.other.as_mut() {
+            other_.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(pairing:Option<Pairing>) -> Option<(Vec<u8>, Vec<u8>)> {
+        match pairing {
+            Some(Pairing::Pair(text1_, text2_)) => Some((text1_, text2_)),
+            Some(Pairing::Unknown(text_)) => Some((text_, Vec::new())),
+            None => None,
+        }
+    }
+
+    #[test]
+    fn pair_mates() {
+        let mut buffer = MateBuffer::new(10);
+        assert!(texts(buffer.add("a", false, b"a2".to_vec())).is_none());
+        assert!(texts(buffer.add("b", true, b"b1".to_vec())).is_none());
+        assert_eq!(texts(buffer.add("a", true, b"a1".to_vec())), Some((b"a1".to_vec(), b"a2".to_vec())));
+        assert_eq!(texts(buffer.add("b", false, b"b2".to_vec())), Some((b"b1".to_vec(), b"b2".to_vec())));
+        assert_eq!(buffer.pop(), None);
+    }
+
+    #[test]
+    fn evict_oldest() {
+        let mut buffer = MateBuffer::new(2);
+        assert!(texts(buffer.add("a", true, b"a1".to_vec())).is_none());
+        assert!(texts(buffer.add("b", true, b"b1".to_vec())).is_none());
+        assert_eq!(texts(buffer.add("c", true, b"c1".to_vec())), Some((b"a1".to_vec(), Vec::new())));
+        // the same mate again, the former one is not paired
+        assert_eq!(texts(buffer.add("b", true, b"b1'".to_vec())), Some((b"b1".to_vec(), Vec::new())));
+        assert_eq!(buffer.pop(), Some(b"c1".to_vec()));
+        assert_eq!(buffer.pop(), Some(b"b1'".to_vec()));
+        assert_eq!(buffer.pop(), None);
+    }
+
+    #[test]
+    fn paired_writer() {
+        let (mut read1, mut read2, mut singleton, mut main) = (Vec::<u8>::new(), Vec::<u8>::new(), Vec::<u8>::new(), Vec::<u8>::new());
+        let outputs = PairedOutputs{read1:&mut read1, read2:&mut read2, singleton:Some(&mut singleton), other:None};
+        let mut writer = PairedWriter::new(outputs, 10);
+        writer.write(0x41, "a", b"a1\n", &mut main).unwrap();
+        writer.write(0x81, "a", b"a2\n", &mut main).unwrap();
+        writer.write(0x0, "u", b"u\n", &mut main).unwrap();
+        writer.write(0x41, "b", b"b1\n", &mut main).unwrap();
+        writer.finish(true, &mut main).unwrap();
+        assert_eq!((writer.n_pairs, writer.n_orphans, writer.n_unknown), (1, 1, 0));
+        drop(writer);
+        assert_eq!((read1, read2, singleton, main), (b"a1\n".to_vec(), b"a2\n".to_vec(), b"b1\n".to_vec(), b"u\n".to_vec()));
+    }
+}
@@ -0,0 +1,134 @@
+// Source of decompressed BGZF blocks.
+// Blocks are framed by a reader thread, inflated and validated by worker threads and put back in order,
+// or processed one by one in the calling thread.
+
+use std::io::Read;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::thread;
+
+use crate::bamloader::{BamErrorKind, BamHandleError, BlockInfo, BlockOptions, BlockReader, RawBlock,
+    frame_next_block, decompress_and_validate};
+
+// Number of blocks read ahead of the consumer per worker
+const BLOCKS_PER_WORKER:usize = 8;
+
+/// Input stream of blocks, readers are moved into the reader thread
+pub type InputReader = BlockReader<Box<dyn Read + Send>>;
+
+// blocks are passed with the input offset after framing and the compressed bytes consumed in framing,
+// empty unless the reader keeps them
+type FramedBlock = (u64, BlockInfo, Result<RawBlock, BamHandleError>, u64, Vec<u8>);
+type DecodedBlock = (u64, BlockInfo, Result<Vec<u8>, BamHandleError>, u64, Vec<u8>);
+type PendingBlock = (BlockInfo, Result<Vec<u8>, BamHandleError>, u64, Vec<u8>);
+
+pub enum BlockSource {
+    Sequential {
+        reader:InputReader,
+        options:BlockOptions,
+        scanmode:bool,
+    },
+    Parallel {
+        results:Receiver<DecodedBlock>,
+        tokens:Receiver<()>, // one token per block read ahead, returned when the block is consumed
+        pending:HashMap<u64, PendingBlock>,
+        next_index:u64,
+    },
+}
+
+impl BlockSource {
+    /// Blocks are processed in the calling thread if n_threads is less than 2
+    pub fn new(reader:InputReader, options:BlockOptions, n_threads:usize) -> BlockSource {
+        if n_threads < 2 {
+            // the first block is scanned to skip a corrupted header
+            return BlockSource::Sequential{reader, options, scanmode:true};
+        }
+        let window = n_threads * BLOCKS_PER_WORKER;
+        let (token_tx, token_rx) = sync_channel::<()>(window);
+        let (job_tx, job_rx) = sync_channel::<FramedBlock>(window);
+        let (result_tx, result_rx) = channel::<DecodedBlock>();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+
+        for _ in 0..n_threads {
+            let job_rx = Arc::clone(&job_rx);
+            let result_tx = result_tx.clone();
+            let options = options.clone();
+            thread::spawn(move || inflate_blocks(job_rx, result_tx, options));
+        }
+        let chain = options.scan_chain;
+        thread::spawn(move || frame_blocks(reader, chain, job_tx, token_tx));
+
+        BlockSource::Parallel{results:result_rx, tokens:token_rx, pending:HashMap::new(), next_index:0}
+    }
+
+    /// Next block in the order of the file, errors are returned for corrupted blocks and the end of file.
+    /// The input offset after framing the block is set to read_to,
+    /// bytes consumed in framing the block are set to consumed if the reader keeps them.
+    pub fn next_block(&mut self, info:&mut BlockInfo, read_to:&mut u64, consumed:&mut Vec<u8>) -> Result<Vec<u8>, BamHandleError> {
+        match self {
+            BlockSource::Sequential{reader, options, scanmode} => {
+                let block = frame_next_block(reader, scanmode, options.scan_chain, info);
+                *read_to = reader.offset();
+                *consumed = reader.take_consumed();
+                decompress_and_validate(block?, options, info)
+            },
+            BlockSource::Parallel{results, tokens, pending, next_index} => {
+                while !pending.contains_key(next_index) {
+                    match results.recv() {
+                        Ok((index_, info_, result_, read_to_, consumed_)) => {
+                            pending.insert(index_, (info_, result_, read_to_, consumed_));
+                        },
+                        Err(_) => { // all threads finished
+                            return Err(BamHandleError{line:line!(), function:"next_block".to_string(), kind:BamErrorKind::BufferTerminated});
+                        },
+                    }
+                }
+                let (info_, result_, read_to_, consumed_) = pending.remove(next_index).unwrap();
+                *next_index += 1;
+                *info = info_;
+                *read_to = read_to_;
+                *consumed = consumed_;
+                let _ = tokens.recv();
+                result_
+            },
+        }
+    }
+}
+
+// Frame blocks in the reader thread until the end of file or the consumer is dropped
+fn frame_blocks(mut reader:InputReader, chain:usize, jobs:SyncSender<FramedBlock>, tokens:SyncSender<()>) {
+    let mut scanmode = true;
+    let mut index:u64 = 0;
+    loop {
+        if tokens.send(()).is_err() {
+            break;
+        }
+        let mut info = BlockInfo::new(0);
+        let result = frame_next_block(&mut reader, &mut scanmode, chain, &mut info);
+        let consumed = reader.take_consumed();
+        let terminated = matches!(&result, Err(BamHandleError{kind:BamErrorKind::BufferTerminated, ..}));
+        if jobs.send((index, info, result, reader.offset(), consumed)).is_err() || terminated {
+            break;
+        }
+        index += 1;
+    }
+}
+
+// Inflate blocks in a worker thread, framing errors are passed through
+fn inflate_blocks(jobs:Arc<Mutex<Receiver<FramedBlock>>>, results:Sender<DecodedBlock>, options:BlockOptions) {
+    loop {
+        let job = jobs.lock().unwrap().recv();
+        let (index, mut info, framed, read_to, consumed) = match job {
+            Ok(j_) => j_,
+            Err(_) => break,
+        };
+        let result = match framed {
+            Ok(block_) => decompress_and_validate(block_, &options, &mut info),
+            Err(e_) => Err(e_),
+        };
+        if results.send((index, info, result, read_to, consumed)).is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,82 @@
+// Dump of damaged regions for forensic analysis. Compressed bytes of corrupted ranges are written as the input is read,
+// so that files and streams are treated alike and only the last block is held in memory, and data inflated from
+// partially decoded blocks are written as they are. Files are named by their offsets and listed in index.tsv.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+pub(crate) struct Quarantine {
+    dir:PathBuf,
+    index:BufWriter<File>,
+    block:Vec<u8>, // input bytes consumed for the last block
+    block_from:u64,
+    open:Option<(u64, BufWriter<File>)>, // start and file of the corrupted range being written
+}
+
+impl Quarantine {
+    pub(crate) fn create(dir:&str) -> io::Result<Quarantine> {
+        fs::create_dir_all(dir)?;
+        let dir = PathBuf::from(dir);
+        let mut index = BufWriter::new(File::create(dir.join("index.tsv"))?);
+        writeln!(index, "file\tkind\toffset\tend\tsize")?;
+        Ok(Quarantine{dir, index, block:Vec::new(), block_from:0, open:None})
+    }
+
+    /// Keep bytes consumed for the next block, following the last one, and write them to the open range
+    pub(crate) fn keep(&mut self, data:Vec<u8>) -> io::Result<()> {
+        self.block_from += self.block.len() as u64;
+        self.block = data;
+        if let Some((_, file_)) = self.open.as_mut() {
+            file_.write_all(&self.block)?;
+        }
+        Ok(())
+    }
+
+    /// Open a corrupted range from start in the last block, bytes kept later are written until the range is closed
+    pub(crate) fn open_range(&mut self, start:u64) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(self.dir.join(format!("corrupted_{}.bin", start)))?);
+        file.write_all(self.block_slice(start, self.block_from + self.block.len() as u64)?)?;
+        self.open = Some((start, file));
+        Ok(())
+    }
+
+    /// Close the open range at end in the last block, the file is renamed with the end
+    pub(crate) fn close_range(&mut self, end:u64) -> io::Result<()> {
+        let (start, file) = match self.open.take() {
+            Some(o_) => o_,
+            None => return Ok(()),
+        };
+        self.block_slice(start.max(self.block_from), end)?;
+        let file = file.into_inner().map_err(|e_| e_.into_error())?;
+        file.set_len(end - start)?; // bytes of the last block following the range
+        let filename = format!("corrupted_{}_{}.bin", start, end);
+        fs::rename(self.dir.join(format!("corrupted_{}.bin", start)), self.dir.join(&filename))?;
+        writeln!(self.index, "{}\tcorrupted\t{}\t{}\t{}", filename, start, end, end - start)?;
+        self.index.flush()
+    }
+
+    /// Write compressed bytes of the range [start, end) in the last block
+    pub(crate) fn dump_block(&mut self, kind:&str, start:u64, end:u64) -> io::Result<()> {
+        let filename = format!("{}_{}_{}.bin", kind, start, end);
+        fs::write(self.dir.join(&filename), self.block_slice(start, end)?)?;
+        writeln!(self.index, "{}\t{}\t{}\t{}\t{}", filename, kind, start, end, end - start)?;
+        self.index.flush()
+    }
+
+    /// Write data inflated from the block at offset
+    pub(crate) fn dump_data(&mut self, kind:&str, offset:u64, data:&[u8]) -> io::Result<()> {
+        let filename = format!("{}_{}.bin", kind, offset);
+        fs::write(self.dir.join(&filename), data)?;
+        writeln!(self.index, "{}\t{}\t{}\t.\t{}", filename, kind, offset, data.len())?;
+        self.index.flush()
+    }
+
+    // Bytes of the range [start, end) in the last block
+    fn block_slice(&self, start:u64, end:u64) -> io::Result<&[u8]> {
+        if start < self.block_from || end > self.block_from + self.block.len() as u64 || start > end {
+            return Err(io::Error::other(format!("bytes {}-{} are not in the last block", start, end)));
+        }
+        Ok(&self.block[(start - self.block_from) as usize..(end - self.block_from) as usize])
+    }
+}
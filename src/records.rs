@@ -0,0 +1,567 @@
+// Iterator of records recovered from a possibly corrupted BAM file.
+// Corruption found in blocks and in the decompressed stream is reported as events between records.
+
+use std::str;
+use std::io::{self, BufReader, Read};
+use std::collections::VecDeque;
+use std::time::Instant;
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::bamloader::{BamErrorKind, BamHandleError, BlockInfo, BlockReader, RecordCheck, RecordScan,
+    check_record, find_record_start, convert_sequence};
+use crate::pipeline::BlockSource;
+use crate::header::{BamHeader, HeaderParse};
+use crate::tags::{AuxField, parse_aux};
+use crate::quarantine::Quarantine;
+use crate::salvager::{Salvager, SalvageReport};
+
+/// Corruption found while reading records
+#[derive(Debug, Clone, PartialEq)]
+pub enum SalvageEvent {
+    /// A block failed in decoding and was discarded with records continuing from previous blocks
+    CorruptedBlock{offset:u64, kind:BamErrorKind},
+    /// A corrupted block was partially decoded, bytes before and after the damage
+    PartialBlock{offset:u64, kind:BamErrorKind, salvaged:usize, tail:usize},
+    /// A corrupted block was repaired by flipping bits
+    RepairedBlock{offset:u64, bits:usize},
+    /// Bytes of the decompressed stream skipped to reach a plausible record start
+    Resync{virtual_offset:u64, skipped:u64},
+    /// A record was discarded since its auxiliary data were malformed at the offset from the start of them
+    CorruptedRecord{virtual_offset:u64, aux_offset:usize},
+}
+
+/// A record passing validation. Fields are decoded from the raw record on demand.
+#[derive(Debug, Clone)]
+pub struct SalvagedRecord {
+    data:Vec<u8>,            // record including block_size
+    pub virtual_offset:u64,  // BGZF virtual offset of the record start
+    pub partial:bool,        // the record ends in the salvaged part of a corrupted block
+    pub after_resync:bool,   // the record follows bytes skipped by resync, e.g. after a corrupted block
+}
+
+// Offset of auxiliary data in a record including block_size
+fn aux_ptr(data:&[u8]) -> usize {
+    let l_seq = LittleEndian::read_u32(&data[20..24]) as usize;
+    36 + data[12] as usize + LittleEndian::read_u16(&data[16..18]) as usize * 4 + l_seq.div_ceil(2) + l_seq
+}
+
+impl SalvagedRecord {
+    fn seq_ptr(&self) -> usize {
+        36 + self.data[12] as usize + self.n_cigar_op() * 4
+    }
+
+    fn qual_ptr(&self) -> usize {
+        self.seq_ptr() + self.l_seq().div_ceil(2)
+    }
+
+    fn n_cigar_op(&self) -> usize {
+        LittleEndian::read_u16(&self.data[16..18]) as usize
+    }
+
+    pub fn l_seq(&self) -> usize {
+        LittleEndian::read_u32(&self.data[20..24]) as usize
+    }
+
+    pub fn name(&self) -> &str {
+        let l_read_name = self.data[12] as usize;
+        str::from_utf8(&self.data[36..35 + l_read_name]).unwrap_or("")
+    }
+
+    pub fn flag(&self) -> u16 {
+        LittleEndian::read_u16(&self.data[18..20])
+    }
+
+    /// Flag 0x1, paired in sequencing
+    pub fn is_paired(&self) -> bool {
+        self.flag() & 0x1 != 0
+    }
+
+    /// Flag 0x4
+    pub fn is_unmapped(&self) -> bool {
+        self.flag() & 0x4 != 0
+    }
+
+    /// Flag 0x10, SEQ is reverse-complemented and QUAL is reversed
+    pub fn is_reverse(&self) -> bool {
+        self.flag() & 0x10 != 0
+    }
+
+    /// Flag 0x40
+    pub fn is_read1(&self) -> bool {
+        self.flag() & 0x40 != 0
+    }
+
+    /// Flag 0x80
+    pub fn is_read2(&self) -> bool {
+        self.flag() & 0x80 != 0
+    }
+
+    /// Flag 0x100
+    pub fn is_secondary(&self) -> bool {
+        self.flag() & 0x100 != 0
+    }
+
+    /// Flag 0x800
+    pub fn is_supplementary(&self) -> bool {
+        self.flag() & 0x800 != 0
+    }
+
+    pub fn ref_id(&self) -> i32 {
+        LittleEndian::read_i32(&self.data[4..8])
+    }
+
+    pub fn pos(&self) -> i32 {
+        LittleEndian::read_i32(&self.data[8..12])
+    }
+
+    pub fn mapq(&self) -> u8 {
+        self.data[13]
+    }
+
+    pub fn next_ref_id(&self) -> i32 {
+        LittleEndian::read_i32(&self.data[24..28])
+    }
+
+    pub fn next_pos(&self) -> i32 {
+        LittleEndian::read_i32(&self.data[28..32])
+    }
+
+    pub fn tlen(&self) -> i32 {
+        LittleEndian::read_i32(&self.data[32..36])
+    }
+
+    /// CIGAR operations, length << 4 | operation
+    pub fn cigar(&self) -> Vec<u32> {
+        let start = 36 + self.data[12] as usize;
+        (0..self.n_cigar_op()).map(|i| LittleEndian::read_u32(&self.data[start + i * 4..start + i * 4 + 4])).collect()
+    }
+
+    /// Bases in IUPAC letters
+    pub fn seq(&self) -> String {
+        convert_sequence(&self.data, self.seq_ptr(), self.l_seq())
+    }
+
+    /// Phred scores without offset, all 0xFF if absent
+    pub fn qual(&self) -> &[u8] {
+        let start = self.qual_ptr();
+        &self.data[start..start + self.l_seq()]
+    }
+
+    /// False if QUAL is filled with 0xFF, as secondary alignments often are
+    pub fn has_qual(&self) -> bool {
+        self.qual().first() != Some(&0xff)
+    }
+
+    /// Raw bytes of auxiliary fields
+    pub fn aux(&self) -> &[u8] {
+        &self.data[self.qual_ptr() + self.l_seq()..]
+    }
+
+    /// Decoded auxiliary fields
+    pub fn tags(&self) -> Vec<AuxField> {
+        parse_aux(self.aux()).unwrap_or_default()
+    }
+
+    /// Auxiliary field of the tag such as CB
+    pub fn tag(&self, tag:&str) -> Option<AuxField> {
+        self.tags().into_iter().find(|f_| f_.tag == tag)
+    }
+
+    /// File offset of the BGZF block where the record starts
+    pub fn block_offset(&self) -> u64 {
+        self.virtual_offset >> 16
+    }
+
+    /// Offset of the record start in the decompressed block
+    pub fn offset_in_block(&self) -> u64 {
+        self.virtual_offset & 0xffff
+    }
+
+    /// Raw bytes of the record including block_size
+    pub fn raw(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// Records and corruption events in the order of the file
+pub struct RecordIterator {
+    source:BlockSource,
+    header:Option<BamHeader>,
+    n_ref:Option<usize>,
+    verbose:bool,
+    filesize_known:bool, // false for streams
+    buffer:Vec<u8>,
+    block_info:BlockInfo,
+    read_to:u64,    // input offset of the bytes consumed by the reader
+    stream_pos:u64, // position of buffer head in decompressed stream
+    block_starts:VecDeque<(u64,u64,u64,usize)>, // stream position, file offset, offset in the block and map index of buffered blocks
+    resync:bool,    // true if the head of buffer is not a record boundary
+    resync_from:u64, // stream position where resync started
+    resynced:bool,  // true until a record following the resync is returned
+    partial:bool,   // true if the last block was partially inflated
+    truncated:bool, // true if data following the buffer are lost
+    tail:Option<Vec<u8>>, // bytes inflated after the damage of the last block
+    finished:bool,
+    events:VecDeque<SalvageEvent>,
+    held:Option<SalvagedRecord>, // record returned after pending events
+    damaged_from:Option<u64>, // start of the file range not decoded completely
+    block_map:Option<Vec<BlockInfo>>,
+    report:SalvageReport,
+    started:Instant,
+    quarantine:Option<Quarantine>,
+}
+
+impl RecordIterator {
+    /// Open BAM file, or stdin if the name is "-", and read the header block
+    pub(crate) fn open(settings:&Salvager) -> Result<RecordIterator, BamHandleError> {
+        if settings.input == "-" {
+            return RecordIterator::from_reader(settings, Box::new(io::stdin()), None);
+        }
+        let file_in = match std::fs::File::open(&settings.input) {
+            Ok(f_) => f_,
+            Err(_) => {
+                return Err(BamHandleError{line:line!(), function:"open".to_string(), kind:BamErrorKind::NoBAMFile});
+            },
+        };
+        let filesize = match file_in.metadata() {
+            Ok(m_) => m_.len(),
+            Err(_) => {
+                return Err(BamHandleError{line:line!(), function:"open".to_string(), kind:BamErrorKind::NoBAMFile});
+            },
+        };
+        RecordIterator::from_reader(settings, Box::new(file_in), Some(filesize))
+    }
+
+    /// Read BAM data from a stream, filesize is determined at the end of the stream if not given
+    pub(crate) fn from_reader(settings:&Salvager, reader:Box<dyn Read + Send>, filesize:Option<u64>) -> Result<RecordIterator, BamHandleError> {
+        let started = Instant::now();
+        if settings.verbose {
+            eprintln!("processing {}", settings.input);
+        }
+        let mut reader = BlockReader::new(Box::new(BufReader::new(reader)) as Box<dyn Read + Send>);
+        if settings.quarantine.is_some() { // compressed bytes are dumped from those consumed by the reader
+            reader.keep_consumed();
+        }
+        let source = BlockSource::new(reader, settings.block_options.clone(), settings.threads);
+        let block_map:Option<Vec<BlockInfo>> = if settings.block_map { Some(Vec::new()) } else { None };
+
+        let quarantine = match settings.quarantine.as_ref() {
+            Some(dir_) => {
+                match Quarantine::create(dir_) {
+                    Ok(q_) => Some(q_),
+                    Err(_) => return Err(BamHandleError{line:line!(), function:"from_reader".to_string(), kind:BamErrorKind::OutputFailed}),
+                }
+            },
+            None => None,
+        };
+        let report = SalvageReport{input:settings.input.clone(), filesize:filesize.unwrap_or(0), ..Default::default()};
+        let mut records = RecordIterator{source, header:None, n_ref:None, verbose:settings.verbose, filesize_known:filesize.is_some(),
+            buffer:Vec::new(), block_info:BlockInfo::new(0), read_to:0, stream_pos:0, block_starts:VecDeque::new(),
+            resync:false, resync_from:0, resynced:false, partial:false, truncated:false, tail:None, finished:false,
+            events:VecDeque::new(), held:None, damaged_from:None, block_map, report, started,
+            quarantine};
+
+        // header, if the data block is corrupted, skip the part
+        records.fetch_block();
+        // Assert BAM\1
+        if records.buffer.starts_with(&[66, 65, 77, 1]) {
+            records.read_header(settings.header.as_ref());
+        } else if settings.headerless || settings.header.is_some() {
+            eprintln!("BAM header is not found");
+            records.use_header(settings.header.as_ref());
+        } else {
+            let kind = match records.events.front() {
+                Some(SalvageEvent::CorruptedBlock{kind:kind_, ..}) => *kind_,
+                _ if records.finished => BamErrorKind::BufferTerminated,
+                _ => BamErrorKind::IncorrectMagicNumber,
+            };
+            return Err(BamHandleError{line:line!(), function:"open".to_string(), kind});
+        }
+        Ok(records)
+    }
+
+    // Decode header spanning one or more blocks, records start right after the header
+    fn read_header(&mut self, fallback:Option<&BamHeader>) {
+        loop {
+            match BamHeader::parse(&self.buffer) {
+                HeaderParse::Complete(header_, size_) => {
+                    self.buffer.drain(0..size_);
+                    self.stream_pos = size_ as u64;
+                    self.n_ref = Some(header_.references.len());
+                    self.header = Some(header_);
+                    return;
+                },
+                HeaderParse::Incomplete if !self.finished && !self.resync && !self.truncated && self.tail.is_none() => {
+                    self.fetch_block();
+                },
+                _ => { // header is lost, records are searched from the following bytes
+                    eprintln!("BAM header is corrupted");
+                    self.use_header(fallback);
+                    return;
+                },
+            }
+        }
+    }
+
+    // Search records without the header of the file. References of a header given instead are not those of the
+    // records, which are kept and written as unplaced if their reference IDs are out of it.
+    fn use_header(&mut self, header:Option<&BamHeader>) {
+        self.header = header.cloned();
+        self.n_ref = None;
+        self.start_resync();
+    }
+
+    /// Header of the BAM file, None if it was not recovered
+    pub fn header(&self) -> Option<&BamHeader> {
+        self.header.as_ref()
+    }
+
+    /// Counts and positions so far. Sequence counts are those of records returned.
+    pub fn report(&self) -> SalvageReport {
+        let mut report = self.report.clone();
+        if let Some(from_) = self.damaged_from {
+            report.corrupted_ranges.push((from_, self.read_to));
+        }
+        if let Some(map_) = self.block_map.as_ref() {
+            report.block_map = map_.clone();
+        }
+        report.elapsed = self.started.elapsed();
+        report
+    }
+
+    // Salvage goes on without the dump if it fails
+    fn dump<F:FnOnce(&mut Quarantine) -> io::Result<()>>(&mut self, dump:F) {
+        if let Some(quarantine_) = self.quarantine.as_mut() {
+            if let Err(e_) = dump(quarantine_) {
+                eprintln!("quarantine stopped: {}", e_);
+                self.quarantine = None;
+            }
+        }
+    }
+
+    fn start_resync(&mut self) {
+        if !self.resync {
+            self.resync = true;
+            self.resync_from = self.stream_pos;
+        }
+    }
+
+    // Discard buffered bytes, records continuing from them are lost
+    fn discard_buffer(&mut self) {
+        self.start_resync();
+        self.stream_pos += self.buffer.len() as u64;
+        self.buffer.clear();
+        self.block_starts.clear();
+    }
+
+    // Virtual offset of the buffer head, the map row of its block is also returned
+    fn locate_head(&mut self) -> (u64, Option<usize>) {
+        while self.block_starts.len() > 1 && self.block_starts[1].0 <= self.stream_pos {
+            self.block_starts.pop_front();
+        }
+        match self.block_starts.front() {
+            Some(&(start_, offset_, in_block_, index_)) => ((offset_ << 16) | ((in_block_ + self.stream_pos - start_) & 0xffff), Some(index_)),
+            None => (0, None),
+        }
+    }
+
+    // Append next block to buffer
+    fn fetch_block(&mut self) {
+        if let Some(tail_) = self.tail.take() { // bytes recovered after the damage follow a gap
+            let index = self.block_map.as_ref().map_or(0, |m_| m_.len() - 1);
+            let in_block = self.block_info.isize.saturating_sub(tail_.len()) as u64; // the tail ends the block
+            self.block_starts.push_back((self.stream_pos, self.block_info.offset, in_block, index));
+            self.buffer = tail_;
+            self.partial = true;
+            self.truncated = false;
+            self.start_resync();
+            return;
+        }
+        let mut consumed:Vec<u8> = Vec::new();
+        let result = self.source.next_block(&mut self.block_info, &mut self.read_to, &mut consumed);
+        self.dump(|q_| q_.keep(consumed));
+        let block_end = if result.is_ok() { self.block_info.offset + self.block_info.bsize as u64 + 1 } else { self.block_info.offset };
+        self.report.bytes_scanned = self.report.bytes_scanned.max(block_end);
+        if !self.filesize_known { // size of streams grows as bytes are read
+            self.report.filesize = self.read_to;
+        }
+        match result { // fill buffer until the end of block
+            Ok(mut data_) => {
+                let info = self.block_info.clone();
+                self.partial = info.is_partial();
+                self.truncated = self.partial;
+                if info.repaired > 0 {
+                    self.report.repaired_blocks.push(info.offset);
+                    self.events.push_back(SalvageEvent::RepairedBlock{offset:info.offset, bits:info.repaired});
+                    self.dump(|q_| q_.dump_block("repaired", info.offset, info.offset + info.bsize as u64 + 1));
+                }
+                if let Some(from_) = self.damaged_from.take() {
+                    self.report.corrupted_ranges.push((from_, info.offset));
+                    self.dump(|q_| q_.close_range(info.offset));
+                }
+                if info.tail > 0 {
+                    self.tail = Some(data_.split_off(data_.len() - info.tail));
+                }
+                if self.partial { // bytes inflated before and after the damage
+                    self.dump(|q_| q_.dump_data("salvaged", info.offset, &data_));
+                    if let Some(tail_) = self.tail.as_ref() {
+                        let tail = tail_.clone();
+                        self.dump(|q_| q_.dump_data("tail", info.offset, &tail));
+                    }
+                }
+                if self.partial {
+                    #[cfg(debug_assertions)]
+                    eprintln!("{} bytes salvaged from corrupted block at {}", data_.len(), info.offset);
+                    self.report.n_corrupted_blocks += 1;
+                    self.report.n_partial_blocks += 1;
+                    self.damaged_from = Some(info.offset);
+                    self.dump(|q_| q_.open_range(info.offset));
+                    self.events.push_back(SalvageEvent::PartialBlock{offset:info.offset, kind:info.error.unwrap(),
+                        salvaged:info.salvaged, tail:info.tail});
+                }
+                let index = self.block_map.as_ref().map_or(0, |m_| m_.len());
+                self.block_starts.push_back((self.stream_pos + self.buffer.len() as u64, info.offset, 0, index));
+                if let Some(map_) = self.block_map.as_mut() {
+                    map_.push(info.clone());
+                }
+                if self.buffer.is_empty() {self.buffer = data_} else {self.buffer.append(&mut data_)}
+                self.report.n_blocks += 1;
+            },
+            Err(BamHandleError{kind:BamErrorKind::BufferTerminated, ..}) => {
+                #[cfg(debug_assertions)]
+                eprintln!("no more BGZF block found");
+                self.finished = true;
+                if let Some(from_) = self.damaged_from.take() { // damaged until the end of file
+                    let end = self.read_to;
+                    self.report.corrupted_ranges.push((from_, end));
+                    self.dump(|q_| q_.close_range(end));
+                }
+            },
+            Err(e_) => {
+                #[cfg(debug_assertions)]
+                eprintln!("corrupted block detected at {}.", self.block_info.offset);
+                self.block_info.error = Some(e_.kind);
+                if let Some(map_) = self.block_map.as_mut() {
+                    map_.push(self.block_info.clone());
+                }
+                if self.damaged_from.is_none() {
+                    let offset = self.block_info.offset;
+                    self.damaged_from = Some(offset);
+                    self.dump(|q_| q_.open_range(offset));
+                }
+                self.report.n_corrupted_blocks += 1;
+                self.events.push_back(SalvageEvent::CorruptedBlock{offset:self.block_info.offset, kind:e_.kind});
+                self.discard_buffer();
+            },
+        }
+    }
+
+    // Take a record from the head of buffer, None if more data are required
+    fn parse_record(&mut self) -> Option<SalvagedRecord> {
+        loop {
+            if self.resync { // skip bytes until a plausible record start
+                match find_record_start(&self.buffer, 0, self.n_ref) {
+                    RecordScan::Found(pos_) => {
+                        #[cfg(debug_assertions)]
+                        eprintln!("record start found after {} bytes", pos_);
+                        self.buffer.drain(0..pos_);
+                        self.stream_pos += pos_ as u64;
+                        self.resync = false;
+                        self.resynced = true;
+                        self.report.n_resync += 1;
+                        let (virtual_offset, _) = self.locate_head();
+                        self.events.push_back(SalvageEvent::Resync{virtual_offset, skipped:self.stream_pos - self.resync_from});
+                    },
+                    RecordScan::NeedMore(pos_) if self.finished && pos_ < self.buffer.len() => {
+                        // no more data complete the candidate at the end of the stream
+                        self.buffer.drain(0..pos_ + 1);
+                        self.stream_pos += pos_ as u64 + 1;
+                        continue;
+                    },
+                    RecordScan::NeedMore(pos_) => {
+                        self.buffer.drain(0..pos_);
+                        self.stream_pos += pos_ as u64;
+                        return None;
+                    },
+                }
+            }
+            let drain_pos = match check_record(&self.buffer, 0, self.n_ref) {
+                RecordCheck::Plausible(size_) if !self.finished || size_ <= self.buffer.len() => size_,
+                RecordCheck::Incomplete if !self.finished => return None,
+                _ if self.buffer.is_empty() => return None,
+                _ => {
+                    self.start_resync();
+                    self.buffer.drain(0..1);
+                    self.stream_pos += 1;
+                    continue;
+                },
+            };
+            if drain_pos > self.buffer.len() { // fill buffer
+                return None;
+            }
+            let (virtual_offset, index) = self.locate_head();
+            if let Err(pos_) = parse_aux(&self.buffer[aux_ptr(&self.buffer)..drain_pos]) { // malformed tags
+                #[cfg(debug_assertions)]
+                eprintln!("malformed auxiliary data at {} of record at {}", pos_, virtual_offset);
+                self.events.push_back(SalvageEvent::CorruptedRecord{virtual_offset, aux_offset:pos_});
+                self.report.n_corrupted_records += 1;
+                self.start_resync();
+                self.buffer.drain(0..1);
+                self.stream_pos += 1;
+                continue;
+            }
+            if let (Some(map_), Some(index_)) = (self.block_map.as_mut(), index) { // count the record in the block it starts
+                map_[index_].n_records += 1;
+            }
+            let data:Vec<u8> = self.buffer.drain(0..drain_pos).collect();
+            self.stream_pos += drain_pos as u64;
+            let record = SalvagedRecord{data, virtual_offset, partial:self.partial, after_resync:self.resynced};
+            self.resynced = false;
+            self.report.n_sequences += 1;
+            self.report.n_bases += record.l_seq() as u64;
+            if record.partial {
+                self.report.n_partial_sequences += 1;
+            }
+            if self.verbose && self.report.n_sequences.is_multiple_of(1000) {
+                let progress = if self.filesize_known {
+                    format!("{:.1}%", self.block_info.offset as f32 * 100.0 / (self.report.filesize as f32))
+                } else {
+                    format!("{}MB", self.block_info.offset >> 20)
+                };
+                eprint!("\x1B {} {}k reads / {}k blocks / {} corrupted  {}\r",
+                    progress, self.report.n_sequences / 1000, self.report.n_blocks / 1000, self.report.n_corrupted_blocks, record.name())
+            }
+            return Some(record);
+        }
+    }
+}
+
+impl Iterator for RecordIterator {
+    type Item = Result<SalvagedRecord, SalvageEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event_) = self.events.pop_front() {
+                return Some(Err(event_));
+            }
+            if let Some(record_) = self.held.take() {
+                return Some(Ok(record_));
+            }
+            if let Some(record_) = self.parse_record() {
+                if self.events.is_empty() {
+                    return Some(Ok(record_));
+                }
+                self.held = Some(record_); // events found in parsing precede the record
+                continue;
+            }
+            if self.finished { // bytes left at the end of the stream are not records
+                return None;
+            }
+            if self.truncated { // data after the salvaged bytes are lost
+                self.discard_buffer();
+                self.truncated = false;
+            }
+            self.fetch_block();
+        }
+    }
+}
@@ -0,0 +1,333 @@
+// Typed options and results of salvage
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use crate::bamloader::{self, BamHandleError, BlockInfo, BlockOptions};
+use crate::records::RecordIterator;
+use crate::header::BamHeader;
+use crate::pairing::PairedOutputs;
+
+/// Format of recovered reads
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Fastq,
+    Fasta, // FASTQ without QUAL
+    Bam,
+    Sam,
+}
+
+/// Output of reads without QUAL in FASTQ
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MissingQual {
+    Fasta,           // FASTA record among FASTQ records
+    Placeholder(u8), // Phred score repeated for each base
+}
+
+/// Builder of a salvage run.
+///
+/// ```no_run
+/// let report = bamsalvage::Salvager::new("input.bam")
+///     .partial(true)
+///     .threads(4)
+///     .run(&mut std::io::stdout())
+///     .unwrap();
+/// eprintln!("{} reads", report.n_sequences);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Salvager {
+    pub(crate) input:String,
+    pub(crate) format:OutputFormat,
+    pub(crate) limit:u64,
+    pub(crate) verbose:bool,
+    pub(crate) threads:usize,
+    pub(crate) block_map:bool,
+    pub(crate) block_options:BlockOptions,
+    pub(crate) headerless:bool,
+    pub(crate) header:Option<BamHeader>,
+    pub(crate) missing_qual:MissingQual,
+    pub(crate) original_orientation:bool,
+    pub(crate) require_flags:u16,
+    pub(crate) exclude_flags:Option<u16>, // None for the default of the output format
+    pub(crate) mate_buffer:usize,
+    pub(crate) tags:Vec<String>,
+    pub(crate) provenance_tags:bool,
+    pub(crate) provenance_tsv:Option<String>,
+    pub(crate) quarantine:Option<String>,
+}
+
+impl Salvager {
+    pub fn new(input:&str) -> Salvager {
+        Salvager{input:input.to_string(), format:OutputFormat::Fastq, limit:0, verbose:false, threads:1,
+            block_map:false, block_options:BlockOptions{scan_chain:1, ..BlockOptions::default()}, headerless:false, header:None,
+            missing_qual:MissingQual::Placeholder(1), original_orientation:true,
+            require_flags:0, exclude_flags:None, mate_buffer:100000, tags:Vec::new(),
+            provenance_tags:false, provenance_tsv:None, quarantine:None}
+    }
+
+    pub fn format(mut self, format:OutputFormat) -> Salvager {
+        self.format = format;
+        self
+    }
+
+    /// Stop after the given number of reads, 0 for no limit
+    pub fn limit(mut self, limit:u64) -> Salvager {
+        self.limit = limit;
+        self
+    }
+
+    /// Progress to stderr
+    pub fn verbose(mut self, verbose:bool) -> Salvager {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Number of threads decompressing blocks, 1 to process in the calling thread
+    pub fn threads(mut self, threads:usize) -> Salvager {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Collect the corruption map into SalvageReport::block_map
+    pub fn block_map(mut self, enabled:bool) -> Salvager {
+        self.block_map = enabled;
+        self
+    }
+
+    /// Keep bytes inflated from corrupted blocks before the damage
+    pub fn partial(mut self, enabled:bool) -> Salvager {
+        self.block_options.salvage_partial = enabled;
+        self
+    }
+
+    /// Search deflate blocks decodable after the damage of corrupted blocks
+    pub fn deflate_resync(mut self, enabled:bool) -> Salvager {
+        self.block_options.deflate_resync = enabled;
+        self
+    }
+
+    /// Repair corrupted blocks by flipping up to 1 or 2 bits, 0 to disable
+    pub fn repair(mut self, bits:usize) -> Salvager {
+        self.block_options.repair_bits = bits.min(2);
+        self
+    }
+
+    /// Number of headers chained by BSIZE to confirm a block found by scan, 0 to accept the first candidate
+    pub fn scan_chain(mut self, depth:usize) -> Salvager {
+        self.block_options.scan_chain = depth;
+        self
+    }
+
+    /// Go on without the header if the first block is lost
+    pub fn headerless(mut self, enabled:bool) -> Salvager {
+        self.headerless = enabled;
+        self
+    }
+
+    /// Header used if the header of the file is lost, such as that of a sibling BAM file
+    pub fn header(mut self, header:BamHeader) -> Salvager {
+        self.header = Some(header);
+        self
+    }
+
+    /// Output of reads having QUAL filled with 0xFF in FASTQ, Phred score 1 by default
+    pub fn missing_qual(mut self, missing_qual:MissingQual) -> Salvager {
+        self.missing_qual = match missing_qual {
+            MissingQual::Placeholder(q_) => MissingQual::Placeholder(q_.min(93)),
+            MissingQual::Fasta => MissingQual::Fasta,
+        };
+        self
+    }
+
+    /// Reverse-complement SEQ and reverse QUAL of reads with flag 0x10 in FASTA/FASTQ, enabled by default
+    pub fn original_orientation(mut self, enabled:bool) -> Salvager {
+        self.original_orientation = enabled;
+        self
+    }
+
+    /// Write only records having all of the flag bits as samtools view -f
+    pub fn require_flags(mut self, flags:u16) -> Salvager {
+        self.require_flags = flags;
+        self
+    }
+
+    /// Skip records having any of the flag bits as samtools view -F. Secondary and supplementary alignments (0x900)
+    /// are skipped by default in FASTA/FASTQ, and all records are written in BAM/SAM.
+    pub fn exclude_flags(mut self, flags:u16) -> Salvager {
+        self.exclude_flags = Some(flags);
+        self
+    }
+
+    // Flags of excluded records, the default depends on the output format
+    pub(crate) fn excluded_flags(&self) -> u16 {
+        match (self.exclude_flags, self.format) {
+            (Some(flags_), _) => flags_,
+            (None, OutputFormat::Bam | OutputFormat::Sam) => 0,
+            (None, _) => 0x900,
+        }
+    }
+
+    /// Number of mates waiting for their partners in split of paired-end reads
+    pub fn mate_buffer(mut self, capacity:usize) -> Salvager {
+        self.mate_buffer = capacity.max(1);
+        self
+    }
+
+    /// Tags such as CB and UB appended to the header lines of FASTA/FASTQ in SAM format
+    pub fn tags<S:AsRef<str>>(mut self, tags:&[S]) -> Salvager {
+        self.tags = tags.iter().map(|t_| t_.as_ref().to_string()).collect();
+        self
+    }
+
+    /// Append the block offset (ZO), the offset in the decompressed block (ZU) and resync before the read (ZR)
+    /// to the header lines of FASTA/FASTQ
+    pub fn provenance_tags(mut self, enabled:bool) -> Salvager {
+        self.provenance_tags = enabled;
+        self
+    }
+
+    /// Write the origin of each written read into a TSV file
+    pub fn provenance_tsv(mut self, filename:&str) -> Salvager {
+        self.provenance_tsv = Some(filename.to_string());
+        self
+    }
+
+    /// Dump compressed bytes of corrupted ranges and repaired blocks and data inflated from partially decoded blocks
+    /// into the directory with index.tsv. Compressed bytes are written as the input is read, for files and streams alike.
+    pub fn quarantine(mut self, dir:&str) -> Salvager {
+        self.quarantine = Some(dir.to_string());
+        self
+    }
+
+    /// Extract reads into output. The input is read from stdin if its name is "-".
+    pub fn run(&self, output:&mut dyn Write) -> Result<SalvageReport, BamHandleError> {
+        bamloader::salvage(self, RecordIterator::open(self)?, output, None)
+    }
+
+    /// Extract reads into files of read 1, read 2, singletons and others in FASTA/FASTQ
+    pub fn run_paired(&self, output:&mut dyn Write, paired:PairedOutputs) -> Result<SalvageReport, BamHandleError> {
+        bamloader::salvage(self, RecordIterator::open(self)?, output, Some(paired))
+    }
+
+    /// Extract reads of BAM data given by a reader, such as a pipe or an in-memory buffer
+    pub fn run_reader<R:Read + Send + 'static>(&self, reader:R, output:&mut dyn Write) -> Result<SalvageReport, BamHandleError> {
+        bamloader::salvage(self, RecordIterator::from_reader(self, Box::new(reader), None)?, output, None)
+    }
+
+    /// Iterate over recovered records and corruption events
+    pub fn records(&self) -> Result<RecordIterator, BamHandleError> {
+        RecordIterator::open(self)
+    }
+
+    /// Iterate over records of BAM data given by a reader
+    pub fn records_from_reader<R:Read + Send + 'static>(&self, reader:R) -> Result<RecordIterator, BamHandleError> {
+        RecordIterator::from_reader(self, Box::new(reader), None)
+    }
+}
+
+/// Version of the JSON report written by SalvageReport::write_json, incremented when fields are changed or removed
+pub const REPORT_SCHEMA_VERSION:u32 = 1;
+
+/// Counts and positions of a salvage run
+#[derive(Debug, Clone, Default)]
+pub struct SalvageReport {
+    pub input:String,
+    pub filesize:u64,
+    pub bytes_scanned:u64,               // end of the last block read
+    pub n_sequences:u64,
+    pub n_bases:u64,
+    pub n_blocks:u64,                    // blocks decoded fully or partially including those of the header
+    pub n_corrupted_blocks:u64,
+    pub n_resync:u64,
+    pub n_partial_blocks:u64,
+    pub n_partial_sequences:u64,
+    pub n_corrupted_records:u64,         // records discarded for malformed auxiliary data
+    pub n_missing_qual:u64,              // reads without QUAL
+    pub n_invalid_modifications:u64,     // reads whose MM/ML/MN are inconsistent with SEQ
+    pub n_filtered_required:u64,         // records lacking flags of Salvager::require_flags
+    pub n_filtered_excluded:u64,         // records having flags of Salvager::exclude_flags
+    pub n_pairs:u64,                     // pairs written by Salvager::run_paired
+    pub n_orphans:u64,                   // mates whose partner was not found
+    pub n_unknown_pairs:u64,             // mates left the buffer before their partner came
+    pub repaired_blocks:Vec<u64>,        // file offsets of repaired blocks
+    pub corrupted_ranges:Vec<(u64,u64)>, // file ranges [start, end) not decoded completely
+    pub block_map:Vec<BlockInfo>,        // empty unless requested by Salvager::block_map
+    pub elapsed:Duration,
+}
+
+// JSON string literal
+fn json_string(text:&str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted += "\\\"",
+            '\\' => quoted += "\\\\",
+            c_ if (c_ as u32) < 0x20 => quoted += &format!("\\u{:04x}", c_ as u32),
+            c_ => quoted.push(c_),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl SalvageReport {
+    /// Compressed bytes not decoded completely, an estimate of data lost
+    pub fn bytes_lost(&self) -> u64 {
+        self.corrupted_ranges.iter().map(|(s_, e_)| e_.saturating_sub(*s_)).sum()
+    }
+
+    /// Write the summary as a JSON object of the schema REPORT_SCHEMA_VERSION
+    pub fn write_json(&self, output:&mut dyn Write) -> io::Result<()> {
+        let n_failed = self.n_corrupted_blocks - self.n_partial_blocks; // blocks not decoded at all
+        let n_ok = self.n_blocks - self.n_partial_blocks - self.repaired_blocks.len() as u64;
+        let repaired:Vec<String> = self.repaired_blocks.iter().map(|o_| o_.to_string()).collect();
+        let ranges:Vec<String> = self.corrupted_ranges.iter().map(|(s_, e_)| format!("[{},{}]", s_, e_)).collect();
+        writeln!(output, "{{")?;
+        writeln!(output, "  \"schema_version\":{},", REPORT_SCHEMA_VERSION)?;
+        writeln!(output, "  \"tool\":{{\"name\":\"bamsalvage\",\"version\":\"{}\"}},", env!("CARGO_PKG_VERSION"))?;
+        writeln!(output, "  \"input\":{{\"path\":{},\"size\":{}}},", json_string(&self.input), self.filesize)?;
+        writeln!(output, "  \"bytes_scanned\":{},", self.bytes_scanned)?;
+        writeln!(output, "  \"bytes_lost\":{},", self.bytes_lost())?;
+        writeln!(output, "  \"blocks\":{{\"total\":{},\"ok\":{},\"corrupted\":{},\"partial\":{},\"repaired\":{}}},",
+            self.n_blocks + n_failed, n_ok, self.n_corrupted_blocks, self.n_partial_blocks, self.repaired_blocks.len())?;
+        writeln!(output, "  \"reads\":{{\"recovered\":{},\"bases\":{},\"partial\":{},\"missing_qual\":{},\"invalid_modifications\":{},\"corrupted_records\":{}}},",
+            self.n_sequences, self.n_bases, self.n_partial_sequences, self.n_missing_qual, self.n_invalid_modifications, self.n_corrupted_records)?;
+        writeln!(output, "  \"filters\":{{\"required\":{},\"excluded\":{}}},", self.n_filtered_required, self.n_filtered_excluded)?;
+        writeln!(output, "  \"pairs\":{{\"pairs\":{},\"orphans\":{},\"unknown\":{}}},", self.n_pairs, self.n_orphans, self.n_unknown_pairs)?;
+        writeln!(output, "  \"resync\":{},", self.n_resync)?;
+        writeln!(output, "  \"repaired_blocks\":[{}],", repaired.join(","))?;
+        writeln!(output, "  \"corrupted_ranges\":[{}],", ranges.join(","))?;
+        writeln!(output, "  \"elapsed_seconds\":{:.3}", self.elapsed.as_secs_f64())?;
+        writeln!(output, "}}")
+    }
+}
+
+impl fmt::Display for SalvageReport {
+    fn fmt(&self, ft:&mut fmt::Formatter) -> fmt::Result {
+        let ranges:Vec<String> = self.corrupted_ranges.iter().map(|(s_, e_)| format!("{}-{}", s_, e_)).collect();
+        let repaired:Vec<String> = self.repaired_blocks.iter().map(|o_| o_.to_string()).collect();
+        writeln!(ft, "filesize={}", self.filesize)?;
+        writeln!(ft, "bytes_scanned={}", self.bytes_scanned)?;
+        writeln!(ft, "bytes_lost={}", self.bytes_lost())?;
+        writeln!(ft, "n_sequences={}", self.n_sequences)?;
+        writeln!(ft, "n_bases={}", self.n_bases)?;
+        writeln!(ft, "n_blocks={}", self.n_blocks)?;
+        writeln!(ft, "n_corrupted={}", self.n_corrupted_blocks)?;
+        writeln!(ft, "n_resync={}", self.n_resync)?;
+        writeln!(ft, "n_partial_blocks={}", self.n_partial_blocks)?;
+        writeln!(ft, "n_partial_sequences={}", self.n_partial_sequences)?;
+        writeln!(ft, "n_corrupted_records={}", self.n_corrupted_records)?;
+        writeln!(ft, "n_missing_qual={}", self.n_missing_qual)?;
+        writeln!(ft, "n_invalid_modifications={}", self.n_invalid_modifications)?;
+        writeln!(ft, "n_filtered_required={}", self.n_filtered_required)?;
+        writeln!(ft, "n_filtered_excluded={}", self.n_filtered_excluded)?;
+        writeln!(ft, "n_pairs={}", self.n_pairs)?;
+        writeln!(ft, "n_orphans={}", self.n_orphans)?;
+        writeln!(ft, "n_unknown_pairs={}", self.n_unknown_pairs)?;
+        writeln!(ft, "n_repaired_blocks={}", self.repaired_blocks.len())?;
+        writeln!(ft, "repaired_blocks={}", repaired.join(","))?;
+        writeln!(ft, "corrupted_ranges={}", ranges.join(","))?;
+        write!(ft, "elapsed={:.3}", self.elapsed.as_secs_f64())
+    }
+}
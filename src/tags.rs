@@ -0,0 +1,178 @@
+// Auxiliary fields of BAM records
+
+use std::fmt;
+use std::str;
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Value of an auxiliary field by its type
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuxValue {
+    Char(u8),               // A
+    Int(i64),               // c, C, s, S, i and I
+    Float(f32),             // f
+    String(String),         // Z
+    Hex(String),            // H
+    IntArray(u8, Vec<i64>), // B with subtype c, C, s, S, i or I
+    FloatArray(Vec<f32>),   // B with subtype f
+}
+
+/// An auxiliary field such as CB:Z:ACGT
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuxField {
+    pub tag:String,
+    pub value:AuxValue,
+}
+
+impl fmt::Display for AuxField {
+    /// SAM text of the field
+    fn fmt(&self, ft:&mut fmt::Formatter) -> fmt::Result {
+        match &self.value {
+            AuxValue::Char(c_) => write!(ft, "{}:A:{}", self.tag, *c_ as char),
+            AuxValue::Int(v_) => write!(ft, "{}:i:{}", self.tag, v_),
+            AuxValue::Float(v_) => write!(ft, "{}:f:{}", self.tag, v_),
+            AuxValue::String(s_) => write!(ft, "{}:Z:{}", self.tag, s_),
+            AuxValue::Hex(s_) => write!(ft, "{}:H:{}", self.tag, s_),
+            AuxValue::IntArray(subtype_, values_) => {
+                write!(ft, "{}:B:{}", self.tag, *subtype_ as char)?;
+                values_.iter().try_for_each(|v_| write!(ft, ",{}", v_))
+            },
+            AuxValue::FloatArray(values_) => {
+                write!(ft, "{}:B:f", self.tag)?;
+                values_.iter().try_for_each(|v_| write!(ft, ",{}", v_))
+            },
+        }
+    }
+}
+
+// Size of an integer or a float of the type, 0 if unknown
+fn value_size(typ:u8) -> usize {
+    match typ {
+        b'A' | b'c' | b'C' => 1,
+        b's' | b'S' => 2,
+        b'i' | b'I' | b'f' => 4,
+        _ => 0,
+    }
+}
+
+fn read_int(typ:u8, data:&[u8]) -> i64 {
+    match typ {
+        b'c' => data[0] as i8 as i64,
+        b'C' => data[0] as i64,
+        b's' => LittleEndian::read_i16(data) as i64,
+        b'S' => LittleEndian::read_u16(data) as i64,
+        b'i' => LittleEndian::read_i32(data) as i64,
+        _ => LittleEndian::read_u32(data) as i64,
+    }
+}
+
+// NUL-terminated text from pos, None if not terminated
+fn read_text(data:&[u8], pos:usize) -> Option<&[u8]> {
+    let end = data[pos..].iter().position(|c_| *c_ == 0)?;
+    Some(&data[pos..pos + end])
+}
+
+/// Decode auxiliary data of a record. The offset of the first malformed field is returned as an error.
+pub(crate) fn parse_aux(data:&[u8]) -> Result<Vec<AuxField>, usize> {
+    let mut fields:Vec<AuxField> = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        if data.len() < pos + 3 || !data[pos].is_ascii_alphabetic() || !data[pos + 1].is_ascii_alphanumeric() {
+            return Err(pos);
+        }
+        let tag = str::from_utf8(&data[pos..pos + 2]).unwrap().to_string();
+        let typ = data[pos + 2];
+        let start = pos + 3;
+        let (value, end) = match typ {
+            b'A' => {
+                if data.len() < start + 1 || !(33..=126).contains(&data[start]) {
+                    return Err(pos);
+                }
+                (AuxValue::Char(data[start]), start + 1)
+            },
+            b'c' | b'C' | b's' | b'S' | b'i' | b'I' => {
+                let size = value_size(typ);
+                if data.len() < start + size {
+                    return Err(pos);
+                }
+                (AuxValue::Int(read_int(typ, &data[start..start + size])), start + size)
+            },
+            b'f' => {
+                if data.len() < start + 4 {
+                    return Err(pos);
+                }
+                (AuxValue::Float(LittleEndian::read_f32(&data[start..start + 4])), start + 4)
+            },
+            b'Z' | b'H' => {
+                let text = match read_text(data, start) {
+                    Some(t_) => t_,
+                    None => return Err(pos),
+                };
+                let end = start + text.len() + 1;
+                // Z is printable characters, H is pairs of hex digits
+                if (typ == b'Z' && text.iter().any(|c_| !(32..=126).contains(c_)))
+                    || (typ == b'H' && (text.len() % 2 != 0 || text.iter().any(|c_| !c_.is_ascii_hexdigit()))) {
+                    return Err(pos);
+                }
+                let text = String::from_utf8_lossy(text).to_string();
+                (if typ == b'Z' { AuxValue::String(text) } else { AuxValue::Hex(text) }, end)
+            },
+            b'B' => {
+                if data.len() < start + 5 {
+                    return Err(pos);
+                }
+                let subtype = data[start];
+                let size = value_size(subtype);
+                let count = LittleEndian::read_u32(&data[start + 1..start + 5]) as usize;
+                if size == 0 || subtype == b'A' || (data.len() - start - 5) / size < count {
+                    return Err(pos);
+                }
+                let values = &data[start + 5..start + 5 + count * size];
+                let value = if subtype == b'f' {
+                    AuxValue::FloatArray(values.chunks(4).map(LittleEndian::read_f32).collect())
+                } else {
+                    AuxValue::IntArray(subtype, values.chunks(size).map(|v_| read_int(subtype, v_)).collect())
+                };
+                (value, start + 5 + count * size)
+            },
+            _ => return Err(pos),
+        };
+        fields.push(AuxField{tag, value});
+        pos = end;
+    }
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_fields() {
+        let mut data:Vec<u8> = Vec::new();
+        data.extend_from_slice(b"XAAy");
+        data.extend_from_slice(b"NMc\xfe");
+        data.extend_from_slice(b"XSS\x10\x27");
+        data.extend_from_slice(b"XFf");
+        data.extend_from_slice(&1.5f32.to_le_bytes());
+        data.extend_from_slice(b"CBZACGT-1\x00");
+        data.extend_from_slice(b"XHH1AE3\x00");
+        data.extend_from_slice(b"MLBC\x02\x00\x00\x00\xc8\x10");
+        data.extend_from_slice(b"XBBf\x01\x00\x00\x00");
+        data.extend_from_slice(&(-0.25f32).to_le_bytes());
+        let fields = parse_aux(&data).unwrap();
+        let text:Vec<String> = fields.iter().map(|f_| f_.to_string()).collect();
+        assert_eq!(text, vec!["XA:A:y", "NM:i:-2", "XS:i:10000", "XF:f:1.5", "CB:Z:ACGT-1", "XH:H:1AE3", "ML:B:C,200,16", "XB:B:f,-0.25"]);
+        assert_eq!(parse_aux(b""), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn malformed_fields() {
+        // offset of the malformed field is returned
+        assert_eq!(parse_aux(b"NMc\x01XXQ\x00"), Err(4));
+        assert_eq!(parse_aux(b"NMc\x01CBZACGT"), Err(4));
+        assert_eq!(parse_aux(b"XHH1AE\x00"), Err(0));
+        assert_eq!(parse_aux(b"MLBC\x05\x00\x00\x00\x01\x02"), Err(0));
+        assert_eq!(parse_aux(b"NMs\x01"), Err(0));
+        assert_eq!(parse_aux(b"1Mc\x01"), Err(0));
+    }
+}